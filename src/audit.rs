@@ -0,0 +1,66 @@
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::Serialize;
+
+use crate::{
+    error::{Context, Result},
+    warn, AUDIT_LOG_FILE,
+};
+
+/// An entry of the audit log, written as a line of JSON.
+#[derive(Debug, Serialize)]
+struct AuditEntry<'a> {
+    timestamp: String,
+    user: String,
+    project: PathBuf,
+    environment: &'a str,
+}
+
+/// Records the activation of the protected environment `env_name` of the project in `project_dir` in the audit log.
+pub(crate) fn record_activation(project_dir: &Path, env_name: &str) -> Result<()> {
+    let Some(dir) = user_state_dir() else {
+        warn(format!(
+            "Could not find the user's state directory. The activation of the protected environment `{}` was not recorded.",
+            env_name
+        ));
+        return Ok(());
+    };
+    let dir = dir.join("activate");
+    fs::create_dir_all(&dir)
+        .context(|| format!("Could not create `{}` directory.", dir.display()))?;
+    let entry = AuditEntry {
+        timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        user: std::env::var("USER")
+            .or_else(|_| std::env::var("USERNAME"))
+            .unwrap_or_else(|_| "unknown".to_string()),
+        project: std::path::absolute(project_dir).unwrap_or_else(|_| project_dir.to_path_buf()),
+        environment: env_name,
+    };
+    let file = dir.join(AUDIT_LOG_FILE);
+    let mut audit_log = File::options()
+        .create(true)
+        .append(true)
+        .open(&file)
+        .context(|| format!("Could not open `{}` file.", file.display()))?;
+    let line = serde_json::to_string(&entry).expect("Could not serialize audit entry") + "\n";
+    audit_log
+        .write_all(line.as_bytes())
+        .context(|| format!("Could not write to `{}` file.", file.display()))
+}
+
+/// `$XDG_STATE_HOME`, or `~/.local/state`.
+fn user_state_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME")
+                .or_else(|| std::env::var_os("USERPROFILE"))
+                .map(|home| PathBuf::from(home).join(".local").join("state"))
+        })
+}
@@ -0,0 +1,185 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{
+    error::{Context, Error, Result},
+    ACTIVATE_DIR, ACTIVATE_STATE_DIR, ACTIVATE_TOML, STATE_DESCENDANTS_FILE,
+};
+
+/// Finds every directory at or below `root` with an `activate.toml` file. Ignores files specified in `.gitignore` and
+/// hidden files. The result of the last walk is reused while none of the walked directories have changed.
+pub(crate) fn find_descendants(root: &Path) -> Result<DescendantsCache> {
+    if let Some(cache) = DescendantsCache::load(root) {
+        if cache.is_fresh(root) {
+            return Ok(cache);
+        }
+    }
+
+    let (tx, rx) = crossbeam_channel::unbounded::<Result<WalkedDir, ignore::Error>>();
+    ignore::WalkBuilder::new(root)
+        .hidden(true)
+        .git_ignore(true)
+        .git_global(false)
+        .git_exclude(false)
+        .parents(true)
+        .threads(num_cpus::get())
+        .build_parallel()
+        .run(|| {
+            let tx = tx.clone();
+            Box::new(move |result| {
+                let entry = match result {
+                    Ok(entry) => entry,
+                    Err(err) => {
+                        tx.send(Err(err)).unwrap();
+                        return ignore::WalkState::Quit;
+                    }
+                };
+                if entry
+                    .file_type()
+                    .is_some_and(|file_type| file_type.is_dir())
+                {
+                    let path = entry.path();
+                    // Stat before looking at the entries, so a change made during the walk makes the cache stale.
+                    let modified = DescendantsCache::modified_times(path);
+                    tx.send(Ok(WalkedDir {
+                        dir: path.strip_prefix(root).unwrap_or(path).to_path_buf(),
+                        modified,
+                        has_activate_toml: path.join(ACTIVATE_TOML).exists(),
+                        has_activate_dir: path.join(ACTIVATE_DIR).exists(),
+                    }))
+                    .unwrap();
+                }
+                ignore::WalkState::Continue
+            })
+        });
+    drop(tx);
+
+    let mut cache = DescendantsCache {
+        parents: DescendantsCache::parent_ignore_files(root),
+        ..Default::default()
+    };
+    for result in rx {
+        let walked = result.map_err(|source| Error::Walk {
+            root: root.to_path_buf(),
+            source,
+        })?;
+        if walked.has_activate_toml {
+            if !walked.has_activate_dir {
+                cache.without_activate_dir.push(walked.dir.clone());
+            }
+            cache.activate_dirs.push(walked.dir.clone());
+        }
+        cache.modified.insert(walked.dir, walked.modified);
+    }
+    cache.activate_dirs.sort();
+    Ok(cache)
+}
+
+/// A directory found by the walk of [find_descendants].
+struct WalkedDir {
+    dir: PathBuf,
+    modified: Option<ModifiedTimes>,
+    has_activate_toml: bool,
+    has_activate_dir: bool,
+}
+
+/// The ignore files read by the walk of [find_descendants], in every walked directory and its parents.
+const IGNORE_FILES: [&'static str; 2] = [".gitignore", ".ignore"];
+
+/// The last modified time of a directory and of each of its [IGNORE_FILES].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct ModifiedTimes {
+    dir: SystemTime,
+    ignore_files: Vec<Option<SystemTime>>,
+}
+
+/// The directories with an `activate.toml` file found by the last walk of a directory. Stored in the state directory
+/// of the walked directory.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct DescendantsCache {
+    /// Directories with an `activate.toml` file, relative to the walked directory.
+    pub(crate) activate_dirs: Vec<PathBuf>,
+    /// Every walked directory, relative to the walked directory, with the modified times recorded while walking it.
+    /// Adding or removing an entry changes the modified time of a directory.
+    modified: HashMap<PathBuf, Option<ModifiedTimes>>,
+    /// The modified times of the [IGNORE_FILES] in every parent of the walked directory, which also apply to the walk.
+    parents: HashMap<PathBuf, Vec<Option<SystemTime>>>,
+    /// Directories with an `activate.toml` file, but without an `.activate` directory when walked. Activation creates
+    /// it, which changes their modified times.
+    #[serde(skip)]
+    without_activate_dir: Vec<PathBuf>,
+}
+
+impl DescendantsCache {
+    fn load(root: &Path) -> Option<DescendantsCache> {
+        let contents = fs::read_to_string(Self::file(root)).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    /// Saves the cache, if `root` has a state directory. The modified times recorded while walking are kept, except for
+    /// directories in which the activation created the `.activate` directory, and the `changed_dirs` in which it created
+    /// or removed links, templates or outputs.
+    pub(crate) fn save(&self, root: &Path, changed_dirs: &HashSet<PathBuf>) -> Result<()> {
+        let file = Self::file(root);
+        if !file.parent().unwrap().exists() {
+            return Ok(());
+        }
+        let mut modified = self.modified.clone();
+        for dir in self.without_activate_dir.iter().chain(changed_dirs) {
+            // directories that were not walked, e.g. ignored ones, stay unrecorded
+            if modified.contains_key(dir) {
+                modified.insert(dir.clone(), Self::modified_times(&root.join(dir)));
+            }
+        }
+        let cache = DescendantsCache {
+            activate_dirs: self.activate_dirs.clone(),
+            modified,
+            parents: self.parents.clone(),
+            without_activate_dir: Vec::new(),
+        };
+        let contents = serde_json::to_string(&cache).expect("Could not serialize descendants.");
+        fs::write(&file, contents)
+            .context(|| format!("Could not write to `{}` file.", file.display()))
+    }
+
+    fn is_fresh(&self, root: &Path) -> bool {
+        self.parents == Self::parent_ignore_files(root)
+            && self.modified.iter().all(|(dir, modified)| {
+                modified.is_some() && *modified == Self::modified_times(&root.join(dir))
+            })
+    }
+
+    fn modified_times(dir: &Path) -> Option<ModifiedTimes> {
+        Some(ModifiedTimes {
+            dir: fs::metadata(dir).and_then(|m| m.modified()).ok()?,
+            ignore_files: Self::ignore_files_modified(dir),
+        })
+    }
+
+    fn ignore_files_modified(dir: &Path) -> Vec<Option<SystemTime>> {
+        IGNORE_FILES
+            .iter()
+            .map(|file| fs::metadata(dir.join(file)).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    /// The modified times of the [IGNORE_FILES] in every parent of `root`.
+    fn parent_ignore_files(root: &Path) -> HashMap<PathBuf, Vec<Option<SystemTime>>> {
+        let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+        root.ancestors()
+            .skip(1)
+            .map(|dir| (dir.to_path_buf(), Self::ignore_files_modified(dir)))
+            .collect()
+    }
+
+    fn file(root: &Path) -> PathBuf {
+        root.join(ACTIVATE_DIR)
+            .join(ACTIVATE_STATE_DIR)
+            .join(STATE_DESCENDANTS_FILE)
+    }
+}
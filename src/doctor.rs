@@ -0,0 +1,265 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::{Context, Result},
+    project::LinkMode,
+    state::{
+        expand_link, normalize, read_links, read_templates, symlink_destination, write_links,
+        ActiveEnvironmentEnv, ActiveLink,
+    },
+    Project, Workspace, ACTIVATE_DIR, ACTIVATE_STATE_DIR, STATE_ACTIVATION_FILE, STATE_ENV_FILE,
+    STATE_LINKS_FILE, STATE_OUTPUTS_FILE, STATE_TEMPLATES_FILE,
+};
+
+/// An inconsistency in the `.activate` directory of a [Project], found by [Project::diagnose].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Issue {
+    /// A state file holds more than one JSON object, as env variables were recorded again without deactivating.
+    ConcatenatedEnv { file: PathBuf, objects: usize },
+    /// A state file could not be parsed.
+    CorruptedState { file: PathBuf, message: String },
+    /// A link in the links state whose target no longer exists.
+    MissingLink { target: String },
+    /// A symlink to the source of a link, that is not in the links state, while an environment is active.
+    UnrecordedLink { target: String, source: String },
+    /// A symlink to the source of a link, that is not in the links state, while no environment is active.
+    OrphanLink { target: String, source: String },
+}
+
+impl Issue {
+    /// What [Project::repair] does to fix the issue.
+    pub fn fix(&self) -> String {
+        match self {
+            Issue::ConcatenatedEnv { file, .. } => format!(
+                "Merge the objects of `{}` into one, so every variable is unset on deactivation.",
+                file.display()
+            ),
+            Issue::CorruptedState { file, .. } => format!("Reset `{}`.", file.display()),
+            Issue::MissingLink { target } => {
+                format!("Remove `{}` from the links state.", target)
+            }
+            Issue::UnrecordedLink { target, .. } => {
+                format!("Add `{}` to the links state.", target)
+            }
+            Issue::OrphanLink { target, .. } => format!("Remove the symlink `{}`.", target),
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::ConcatenatedEnv { file, objects } => write!(
+                f,
+                "`{}` holds {} env objects instead of one, as an environment was activated without deactivating the previous one.",
+                file.display(),
+                objects
+            ),
+            Issue::CorruptedState { file, message } => {
+                write!(f, "`{}` could not be parsed. {}", file.display(), message)
+            }
+            Issue::MissingLink { target } => write!(
+                f,
+                "The link `{}` is in the links state, but does not exist.",
+                target
+            ),
+            Issue::UnrecordedLink { target, source } => write!(
+                f,
+                "The symlink `{}` to `{}` is not in the links state, so it would not be removed on deactivation.",
+                target, source
+            ),
+            Issue::OrphanLink { target, source } => write!(
+                f,
+                "The symlink `{}` to `{}` was left behind, as no environment is active.",
+                target, source
+            ),
+        }
+    }
+}
+
+impl Project {
+    /// Inspects the `.activate` directory for inconsistencies.
+    pub fn diagnose(&self) -> Result<Vec<Issue>> {
+        let state_dir = self.dir().join(ACTIVATE_DIR).join(ACTIVATE_STATE_DIR);
+        let env_file = state_dir.join(STATE_ENV_FILE);
+        let links_file = state_dir.join(STATE_LINKS_FILE);
+        let templates_file = state_dir.join(STATE_TEMPLATES_FILE);
+        let mut issues = Vec::new();
+
+        if env_file.exists() {
+            match read_env_objects(&env_file)? {
+                Ok(objects) if objects.len() > 1 => issues.push(Issue::ConcatenatedEnv {
+                    file: env_file.clone(),
+                    objects: objects.len(),
+                }),
+                Ok(_) => {}
+                Err(message) => issues.push(Issue::CorruptedState {
+                    file: env_file.clone(),
+                    message,
+                }),
+            }
+        }
+        for file in [&templates_file, &state_dir.join(STATE_OUTPUTS_FILE)] {
+            if file.exists() {
+                if let Err(err) = read_templates(file) {
+                    issues.push(Issue::CorruptedState {
+                        file: file.clone(),
+                        message: err.to_string(),
+                    });
+                }
+            }
+        }
+        let links = if links_file.exists() {
+            match read_links(&links_file) {
+                Ok(links) => links.0.unwrap_or_default(),
+                Err(err) => {
+                    // Without the links state, it is unknown which links are recorded.
+                    issues.push(Issue::CorruptedState {
+                        file: links_file,
+                        message: err.to_string(),
+                    });
+                    return Ok(issues);
+                }
+            }
+        } else {
+            HashMap::new()
+        };
+
+        let mut targets = links.keys().collect::<Vec<_>>();
+        targets.sort();
+        for target in targets {
+            if fs::symlink_metadata(self.dir().join(target)).is_err() {
+                issues.push(Issue::MissingLink {
+                    target: target.clone(),
+                });
+            }
+        }
+
+        let activation_file = state_dir.join(STATE_ACTIVATION_FILE);
+        let is_active = [&env_file, &links_file, &templates_file, &activation_file]
+            .iter()
+            .any(|file| file.exists());
+        for (target, source) in self.unrecorded_symlinks(&links)? {
+            issues.push(if is_active {
+                Issue::UnrecordedLink { target, source }
+            } else {
+                Issue::OrphanLink { target, source }
+            });
+        }
+        Ok(issues)
+    }
+
+    /// Fixes `issue`, found by [Project::diagnose], as described by [Issue::fix].
+    pub fn repair(&self, issue: &Issue) -> Result<()> {
+        let links_file = self
+            .dir()
+            .join(ACTIVATE_DIR)
+            .join(ACTIVATE_STATE_DIR)
+            .join(STATE_LINKS_FILE);
+        match issue {
+            Issue::ConcatenatedEnv { file, .. } => {
+                let objects = read_env_objects(file)?.unwrap_or_default();
+                let merged = objects
+                    .into_iter()
+                    .flat_map(|object| object.0.unwrap_or_default())
+                    .collect::<HashMap<_, _>>();
+                fs::write(
+                    file,
+                    serde_json::to_string(&ActiveEnvironmentEnv(Some(merged)))
+                        .expect("Could not serialize environment variables"),
+                )
+                .context(|| format!("Could not write to `{}` file.", file.display()))
+            }
+            Issue::CorruptedState { file, .. } => fs::remove_file(file)
+                .context(|| format!("Could not remove `{}` file.", file.display())),
+            Issue::MissingLink { target } => {
+                let mut links = read_links(&links_file)?.0.unwrap_or_default();
+                links.remove(target);
+                write_links(&links_file, &links)
+            }
+            Issue::UnrecordedLink { target, source } => {
+                let mut file = File::options()
+                    .create(true)
+                    .append(true)
+                    .open(&links_file)
+                    .context(|| format!("Could not open `{}` file.", links_file.display()))?;
+                let link = ActiveLink {
+                    source: source.clone(),
+                    mode: LinkMode::Symlink,
+                    checksum: None,
+                    dirs: Vec::new(),
+                    allow_outside_root: normalize(target).is_none(),
+                };
+                file.write_all(link.to_entry(target).as_bytes())
+                    .context(|| format!("Could not write to `{}` file.", links_file.display()))
+            }
+            Issue::OrphanLink { target, .. } => {
+                let target = self.dir().join(target);
+                fs::remove_file(&target)
+                    .context(|| format!("Could not remove link `{}`.", target.display()))
+            }
+        }
+    }
+
+    /// Symlinks at the target of a link of any environment, pointing to its source, that are not in `links`.
+    fn unrecorded_symlinks(
+        &self,
+        links: &HashMap<String, ActiveLink>,
+    ) -> Result<Vec<(String, String)>> {
+        let mut unrecorded = HashSet::new();
+        for name in self.environment_names() {
+            let Some(environment) = self.environment(name)? else {
+                continue;
+            };
+            for (key, link) in environment.links.iter() {
+                if link.mode != LinkMode::Symlink {
+                    continue;
+                }
+                // Links whose sources are gone can not be expanded, nor found.
+                let expanded = expand_link(key, link, self.dir()).unwrap_or_default();
+                for (target, link) in expanded {
+                    if links.contains_key(&target) {
+                        continue;
+                    }
+                    let is_link = fs::symlink_metadata(self.dir().join(&target))
+                        .is_ok_and(|metadata| metadata.file_type().is_symlink());
+                    if is_link
+                        && fs::read_link(self.dir().join(&target)).ok()
+                            == Some(symlink_destination(&target, &link.source, self.dir()))
+                    {
+                        unrecorded.insert((target, link.source));
+                    }
+                }
+            }
+        }
+        let mut unrecorded = unrecorded.into_iter().collect::<Vec<_>>();
+        unrecorded.sort();
+        Ok(unrecorded)
+    }
+}
+
+impl Workspace {
+    /// Inspects the `.activate` directory of every project for inconsistencies.
+    pub fn diagnose(&self) -> Result<Vec<(&Project, Vec<Issue>)>> {
+        self.projects()
+            .iter()
+            .map(|project| Ok((project, project.diagnose()?)))
+            .collect()
+    }
+}
+
+/// The JSON objects in the env state `file`, or why they could not be parsed.
+fn read_env_objects(file: &Path) -> Result<std::result::Result<Vec<ActiveEnvironmentEnv>, String>> {
+    let contents = fs::read_to_string(file)
+        .context(|| format!("Could not read `{}` file.", file.display()))?;
+    Ok(serde_json::Deserializer::from_str(&contents)
+        .into_iter::<ActiveEnvironmentEnv>()
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|err| err.to_string()))
+}
@@ -0,0 +1,185 @@
+use std::{io, ops::Range, path::PathBuf};
+
+use crate::ACTIVATE_TOML;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("No `{ACTIVATE_TOML}` file found in `{}`.", .0.display())]
+    NoActivateToml(PathBuf),
+    #[error("No environments found in `{}`.", .0.display())]
+    NoEnvironments(PathBuf),
+    #[error("'{env_name}' is not a valid environment in `{}`.", activate_file.display())]
+    UnknownEnvironment {
+        env_name: String,
+        activate_file: PathBuf,
+    },
+    #[error("Could not parse `{}` at line {line}, column {column}. {message}", file.display())]
+    Parse {
+        file: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    #[error(
+        "Could not fully activate environment. Environment variable collision.\n\n`{key}` is defined in `{}` and `{}`",
+        first.display(),
+        second.display()
+    )]
+    Collision {
+        key: String,
+        first: PathBuf,
+        second: PathBuf,
+    },
+    #[error("The source `{}` of link `{link}` in `{}` does not exist.", path.display(), activate_file.display())]
+    MissingSource {
+        link: String,
+        path: PathBuf,
+        activate_file: PathBuf,
+    },
+    #[error("The target `{}` of link `{link}` in `{}` already exists.", path.display(), activate_file.display())]
+    TargetExists {
+        link: String,
+        path: PathBuf,
+        activate_file: PathBuf,
+    },
+    #[error("The link `{link}` in `{}` is invalid. {message}", activate_file.display())]
+    InvalidLink {
+        link: String,
+        activate_file: PathBuf,
+        message: String,
+    },
+    #[error("The template `{}` of `{target}` in `{}` does not exist.", path.display(), activate_file.display())]
+    MissingTemplate {
+        target: String,
+        path: PathBuf,
+        activate_file: PathBuf,
+    },
+    #[error("The target `{}` of template `{target}` in `{}` already exists.", path.display(), activate_file.display())]
+    TemplateTargetExists {
+        target: String,
+        path: PathBuf,
+        activate_file: PathBuf,
+    },
+    #[error("The template `{target}` in `{}` is invalid. {message}", activate_file.display())]
+    InvalidTemplate {
+        target: String,
+        activate_file: PathBuf,
+        message: String,
+    },
+    #[error("The path `{}` of output `{output}` in `{}` already exists.", path.display(), activate_file.display())]
+    OutputExists {
+        output: String,
+        path: PathBuf,
+        activate_file: PathBuf,
+    },
+    #[error("The output `{output}` in `{}` is invalid. {message}", activate_file.display())]
+    InvalidOutput {
+        output: String,
+        activate_file: PathBuf,
+        message: String,
+    },
+    #[error("No active environment found in `{}` or any parent directory.", .0.display())]
+    NoActiveEnvironment(PathBuf),
+    #[error("`{key}` is not defined in the active environment of `{}`.", activate_file.display())]
+    MissingVariable { key: String, activate_file: PathBuf },
+    #[error(
+        "The value{} of `{key}`, from `{}`, is invalid. {message}",
+        value.as_ref().map(|value| format!(" `{value}`")).unwrap_or_default(),
+        activate_file.display()
+    )]
+    InvalidValue {
+        key: String,
+        /// Left out if the value may be secret.
+        value: Option<String>,
+        activate_file: PathBuf,
+        message: String,
+    },
+    #[error("The environment '{env_name}' in `{}` is protected. Its activation must be confirmed, e.g. with `--yes`.", activate_file.display())]
+    ProtectedEnvironment {
+        env_name: String,
+        activate_file: PathBuf,
+    },
+    #[error("The state in `{}` is corrupted. {message}", file.display())]
+    StateCorruption { file: PathBuf, message: String },
+    #[error("{context}")]
+    Io {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Could not walk `{}`.", root.display())]
+    Walk {
+        root: PathBuf,
+        #[source]
+        source: ignore::Error,
+    },
+}
+
+impl Error {
+    /// The process exit code for this class of error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::NoActivateToml(_)
+            | Error::NoEnvironments(_)
+            | Error::UnknownEnvironment { .. }
+            | Error::NoActiveEnvironment(_)
+            | Error::MissingVariable { .. }
+            | Error::InvalidValue { .. } => 2,
+            Error::Parse { .. } => 3,
+            Error::Collision { .. } => 4,
+            Error::MissingSource { .. }
+            | Error::TargetExists { .. }
+            | Error::InvalidLink { .. }
+            | Error::MissingTemplate { .. }
+            | Error::TemplateTargetExists { .. }
+            | Error::InvalidTemplate { .. }
+            | Error::OutputExists { .. }
+            | Error::InvalidOutput { .. } => 5,
+            Error::StateCorruption { .. } => 6,
+            Error::Io { .. } | Error::Walk { .. } => 7,
+            Error::ProtectedEnvironment { .. } => 8,
+        }
+    }
+
+    /// A parse error for `file`, locating `span` in `contents`.
+    pub(crate) fn parse(
+        file: impl Into<PathBuf>,
+        contents: &str,
+        span: Option<Range<usize>>,
+        message: impl Into<String>,
+    ) -> Error {
+        let (line, column) = line_column(contents, span);
+        Error::Parse {
+            file: file.into(),
+            line,
+            column,
+            message: message.into(),
+        }
+    }
+}
+
+pub(crate) trait Context<T> {
+    /// Adds `context`, usually naming the file involved, to an io error.
+    fn context(self, context: impl FnOnce() -> String) -> Result<T>;
+}
+
+impl<T> Context<T> for std::result::Result<T, io::Error> {
+    fn context(self, context: impl FnOnce() -> String) -> Result<T> {
+        self.map_err(|source| Error::Io {
+            context: context(),
+            source,
+        })
+    }
+}
+
+/// The one-based line and column of the start of `span` in `contents`.
+pub(crate) fn line_column(contents: &str, span: Option<Range<usize>>) -> (usize, usize) {
+    let offset = span.map(|span| span.start).unwrap_or(0).min(contents.len());
+    let before = &contents[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let column = before[line_start..].chars().count() + 1;
+    (line, column)
+}
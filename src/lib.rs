@@ -0,0 +1,338 @@
+//! A tool to manage environment-specific configurations. Simplifying working across various settings like Development,
+//! Testing, Production, etc.
+//!
+//! ```no_run
+//! use activate::{ActivateOptions, Workspace};
+//!
+//! # fn main() -> activate::Result<()> {
+//! let workspace = Workspace::discover(".", true)?;
+//! let envs = workspace.activate("dev", &ActivateOptions::default())?;
+//! # Ok(())
+//! # }
+//! ```
+
+#![allow(clippy::redundant_static_lifetimes)]
+
+mod audit;
+mod discover;
+mod doctor;
+mod error;
+mod load;
+mod merge;
+mod output;
+mod project;
+mod state;
+mod template;
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use discover::{find_descendants, DescendantsCache};
+use merge::merge_hierarchy;
+use output::{check_active_files, write_active_files};
+use state::normalize;
+
+pub use doctor::Issue;
+pub use error::{Error, Result};
+pub use load::{emit_cargo_env, load, load_from, ActiveEnv};
+pub use merge::{CollisionStrategy, NewAndOldEnv};
+pub use output::{
+    DotenvFlavor, KubernetesSettings, ManifestSettings, OutputSettings, OutputTarget,
+};
+pub use project::{Environment, Link, LinkCondition, LinkMode, Project, ProjectState, Template};
+
+pub const ACTIVATE_TOML: &'static str = "activate.toml";
+pub const ACTIVATE_DIR: &'static str = ".activate";
+pub const ACTIVATE_STATE_DIR: &'static str = ".state";
+pub const ACTIVATE_ACTIVE_DIR: &'static str = "active";
+pub const STATE_ENV_FILE: &'static str = "env.json";
+pub const ALL_ENV_FILE: &'static str = ".env";
+pub const ALL_ENV_JSON_FILE: &'static str = "env.json";
+pub const ALL_ENV_CONFIGMAP_FILE: &'static str = "configmap";
+pub const ALL_ENV_SECRET_FILE: &'static str = "secret";
+pub const ALL_ENV_SYSTEMD_FILE: &'static str = "systemd.env";
+pub const ALL_ENV_PROPERTIES_FILE: &'static str = "env.properties";
+pub const ALL_ENV_TFVARS_FILE: &'static str = "env.tfvars";
+pub const ALL_ENV_HELM_FILE: &'static str = "values.yaml";
+pub const ALL_ENV_DIRENV_FILE: &'static str = ".envrc";
+pub const STATE_LINKS_FILE: &'static str = "links.toml";
+pub const STATE_TEMPLATES_FILE: &'static str = "templates.toml";
+pub const STATE_OUTPUTS_FILE: &'static str = "outputs.toml";
+pub const STATE_ACTIVATION_FILE: &'static str = "activation.json";
+pub const STATE_DESCENDANTS_FILE: &'static str = "descendants.json";
+/// In the user's state directory, e.g. `~/.local/state/activate`.
+pub const AUDIT_LOG_FILE: &'static str = "audit.log";
+/// Displayed in place of secret values.
+pub const SECRET_MASK: &'static str = "********";
+
+/// A project and, if discovered with descendants, every project below it.
+#[derive(Debug)]
+pub struct Workspace {
+    root: PathBuf,
+    projects: Vec<Project>,
+    descendants: Option<DescendantsCache>,
+}
+
+/// The environment to activate in each project of a [Workspace]. Nothing is changed until it is applied.
+#[derive(Debug)]
+pub struct ActivationPlan<'a> {
+    workspace: &'a Workspace,
+    /// Each project with the environment to activate, or `None` to only deactivate.
+    pub projects: Vec<(&'a Project, Option<Environment>)>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ActivateOptions {
+    /// How to resolve an environment variable defined by more than one project when merging descendants into their
+    /// ancestors.
+    pub collision: CollisionStrategy,
+    /// Name of the configmap to create, overriding the `[<env>.k8s.configmap]` name of every project.
+    pub configmap_name: Option<String>,
+    /// Removes links that were changed since they were created, e.g. symlinks that point somewhere else, instead of
+    /// leaving them in place with a warning.
+    pub force: bool,
+    /// Confirms the activation of protected environments, e.g. after their confirmation phrase was typed.
+    pub confirmed: bool,
+}
+
+impl Workspace {
+    /// Discovers the project in `root` and, if `descendants` is true, every project in its subdirectories. Ignores
+    /// files specified in `.gitignore` and hidden files.
+    pub fn discover(root: impl Into<PathBuf>, descendants: bool) -> Result<Workspace> {
+        let root = root.into();
+        if !root.join(ACTIVATE_TOML).exists() {
+            return Err(Error::NoActivateToml(root));
+        }
+        if descendants {
+            let cache = find_descendants(&root)?;
+            let projects = parallel_map(&cache.activate_dirs, |dir| Project::load(root.join(dir)))
+                .into_iter()
+                .collect::<Result<Vec<_>>>()?;
+            Ok(Workspace {
+                root,
+                projects,
+                descendants: Some(cache),
+            })
+        } else {
+            Ok(Workspace {
+                projects: vec![Project::load(root.clone())?],
+                root,
+                descendants: None,
+            })
+        }
+    }
+
+    /// The directory the workspace was discovered from.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn projects(&self) -> &[Project] {
+        &self.projects
+    }
+
+    /// Resolves the environment `env_name` in every project, or a deactivation if `None`.
+    pub fn resolve(&self, env_name: Option<&str>) -> Result<ActivationPlan<'_>> {
+        let environments = parallel_map(&self.projects, |project| {
+            env_name
+                .map(|env_name| project.expect_environment(env_name))
+                .transpose()
+        })
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+        Ok(ActivationPlan {
+            workspace: self,
+            projects: self.projects.iter().zip(environments).collect(),
+        })
+    }
+
+    /// Activates the environment `env_name` in every project. Returns the merged envs of each project.
+    pub fn activate(&self, env_name: &str, options: &ActivateOptions) -> Result<Vec<NewAndOldEnv>> {
+        self.resolve(Some(env_name))?.apply(options)
+    }
+
+    /// Deactivates the active environment in every project. Returns the merged envs of each project.
+    pub fn deactivate(&self, options: &ActivateOptions) -> Result<Vec<NewAndOldEnv>> {
+        self.resolve(None)?.apply(options)
+    }
+
+    /// Deactivates the projects whose active environment has expired, leaving the others as they are. Returns the envs
+    /// of each project, merged with the envs of its descendants, if any expired.
+    pub fn expire(&self, options: &ActivateOptions) -> Result<Option<Vec<NewAndOldEnv>>> {
+        let states = self.state()?;
+        if !states.iter().any(|(_, state)| state.is_expired()) {
+            return Ok(None);
+        }
+        let planned = parallel_map(&states, |(project, state)| {
+            if state.is_expired() {
+                project.planned(None)
+            } else {
+                project.current(state)
+            }
+        })
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+        self.check_active_files(planned, options)?;
+        let recorded_dirs = self.recorded_dirs()?;
+        let envs = parallel_map(&states, |(project, state)| {
+            if state.is_expired() {
+                project.apply(None, options)
+            } else {
+                project.current(state)
+            }
+        })
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+        self.write_active_files(envs, options, recorded_dirs)
+            .map(Some)
+    }
+
+    /// Merges the planned envs like [Workspace::write_active_files] and renders the `.activate/active` files and
+    /// outputs, without writing anything, so nothing is activated if they can not be written.
+    fn check_active_files(&self, envs: Vec<NewAndOldEnv>, options: &ActivateOptions) -> Result<()> {
+        for env in merge_hierarchy(envs, options.collision)?.iter() {
+            check_active_files(env, options.configmap_name.as_deref())?;
+        }
+        Ok(())
+    }
+
+    /// Merges the envs of each project with the envs of its descendants and writes the `.activate/active` files.
+    /// `recorded_dirs` are the [Workspace::recorded_dirs] before activating.
+    fn write_active_files(
+        &self,
+        envs: Vec<NewAndOldEnv>,
+        options: &ActivateOptions,
+        mut recorded_dirs: HashSet<PathBuf>,
+    ) -> Result<Vec<NewAndOldEnv>> {
+        let envs = merge_hierarchy(envs, options.collision)?;
+        for env in envs.iter() {
+            write_active_files(env, options.configmap_name.as_deref())?;
+        }
+        if let Some(cache) = &self.descendants {
+            recorded_dirs.extend(self.recorded_dirs()?);
+            cache.save(&self.root, &recorded_dirs)?;
+        }
+        Ok(envs)
+    }
+
+    /// The directories, relative to the root, holding the links, templates and outputs recorded in the state of every
+    /// project. Activation adds and removes entries in them, so the descendants cache records them again.
+    fn recorded_dirs(&self) -> Result<HashSet<PathBuf>> {
+        let mut dirs = HashSet::new();
+        if self.descendants.is_none() {
+            return Ok(dirs);
+        }
+        for project in self.projects.iter() {
+            for path in project.recorded_paths()? {
+                // outside of the project
+                let Some(path) = normalize(&path) else {
+                    continue;
+                };
+                let path = project.dir().join(path);
+                if let Some(dir) = path
+                    .parent()
+                    .and_then(|dir| dir.strip_prefix(&self.root).ok())
+                {
+                    dirs.insert(dir.to_path_buf());
+                }
+            }
+        }
+        Ok(dirs)
+    }
+
+    /// Reads what is currently activated in every project.
+    pub fn state(&self) -> Result<Vec<(&Project, ProjectState)>> {
+        self.projects
+            .iter()
+            .map(|project| Ok((project, project.state()?)))
+            .collect()
+    }
+}
+
+impl ActivationPlan<'_> {
+    /// The confirmation phrases of the protected environments to activate, sorted and deduplicated.
+    pub fn confirmation_phrases(&self) -> Vec<&str> {
+        let mut phrases = self
+            .projects
+            .iter()
+            .filter_map(|(_, environment)| environment.as_ref()?.confirmation_phrase.as_deref())
+            .collect::<Vec<_>>();
+        phrases.sort();
+        phrases.dedup();
+        phrases
+    }
+
+    /// Deactivates the active environments, activates the planned ones and writes the `.activate/active` files. Returns
+    /// the envs of each project, merged with the envs of its descendants.
+    ///
+    /// Protected environments are only activated if `options.confirmed` is set, and are recorded in the audit log once
+    /// activated.
+    pub fn apply(self, options: &ActivateOptions) -> Result<Vec<NewAndOldEnv>> {
+        let protected = self
+            .projects
+            .iter()
+            .filter_map(|(project, environment)| {
+                environment
+                    .as_ref()
+                    .filter(|environment| environment.confirmation_phrase.is_some())
+                    .map(|environment| (project, environment))
+            })
+            .collect::<Vec<_>>();
+        if let Some((project, environment)) = protected.first().filter(|_| !options.confirmed) {
+            return Err(Error::ProtectedEnvironment {
+                env_name: environment.name.clone(),
+                activate_file: project.dir().join(ACTIVATE_TOML),
+            });
+        }
+        let planned = parallel_map(&self.projects, |(project, environment)| {
+            project.planned(environment.as_ref())
+        })
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+        self.workspace.check_active_files(planned, options)?;
+        let recorded_dirs = self.workspace.recorded_dirs()?;
+        let envs = parallel_map(&self.projects, |(project, environment)| {
+            project.apply(environment.as_ref(), options)
+        })
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+        let envs = self
+            .workspace
+            .write_active_files(envs, options, recorded_dirs)?;
+        for (project, environment) in protected {
+            audit::record_activation(project.dir(), &environment.name)?;
+        }
+        Ok(envs)
+    }
+}
+
+/// Prints a warning about something that did not fail the command, but needs the user's attention.
+pub(crate) fn warn(message: impl std::fmt::Display) {
+    eprintln!("Warning: {}", message);
+}
+
+/// Prints a notice about something that happened without being asked for, e.g. an expired environment being deactivated.
+pub(crate) fn notice(message: impl std::fmt::Display) {
+    eprintln!("Notice: {}", message);
+}
+
+/// Maps `items` across all cpus, preserving order.
+fn parallel_map<T: Sync, R: Send>(items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let chunk_size = items.len().div_ceil(num_cpus::get()).max(1);
+    std::thread::scope(|scope| {
+        let handles = items
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(|| chunk.iter().map(&f).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .flat_map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+            })
+            .collect()
+    })
+}
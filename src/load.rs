@@ -0,0 +1,162 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::{
+    error::{Context, Error, Result},
+    ACTIVATE_ACTIVE_DIR, ACTIVATE_DIR, ACTIVATE_STATE_DIR, ACTIVATE_TOML, ALL_ENV_JSON_FILE,
+    STATE_ENV_FILE,
+};
+
+/// The env variables of the active environment, as written to a `.activate/active` directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActiveEnv {
+    activate_file: PathBuf,
+    env: HashMap<String, String>,
+}
+
+/// Loads the active environment of the nearest `.activate/active` directory, searching from the current directory up.
+pub fn load() -> Result<ActiveEnv> {
+    let current_dir =
+        std::env::current_dir().context(|| "Could not get the current directory.".to_string())?;
+    load_from(current_dir)
+}
+
+/// For build scripts. Sets `keys`, or every variable if `None`, of the active environment as compile-time env
+/// variables for `env!`, and reruns the build script when the active environment changes.
+pub fn emit_cargo_env(keys: Option<&[&str]>) -> Result<()> {
+    let env = match std::env::var_os("CARGO_MANIFEST_DIR") {
+        Some(manifest_dir) => load_from(manifest_dir)?,
+        None => load()?,
+    };
+    for directive in env.cargo_directives(keys)? {
+        println!("{directive}");
+    }
+    Ok(())
+}
+
+/// Loads the active environment of the nearest `.activate/active` directory, searching from `path` up.
+pub fn load_from(path: impl AsRef<Path>) -> Result<ActiveEnv> {
+    let path = path.as_ref();
+    let start = path
+        .canonicalize()
+        .context(|| format!("Could not resolve `{}`.", path.display()))?;
+    let env_file = start
+        .ancestors()
+        .map(|dir| {
+            dir.join(ACTIVATE_DIR)
+                .join(ACTIVATE_ACTIVE_DIR)
+                .join(ALL_ENV_JSON_FILE)
+        })
+        .find(|env_file| env_file.exists())
+        .ok_or_else(|| Error::NoActiveEnvironment(path.to_path_buf()))?;
+    let project_dir = env_file
+        .parent()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .parent()
+        .unwrap();
+
+    let contents = fs::read_to_string(&env_file)
+        .context(|| format!("Could not read `{}` file.", env_file.display()))?;
+    let env = if contents.trim().is_empty() {
+        HashMap::new()
+    } else {
+        serde_json::from_str(&contents).map_err(|err| Error::Parse {
+            file: env_file.clone(),
+            line: err.line(),
+            column: err.column(),
+            message: err.to_string(),
+        })?
+    };
+    Ok(ActiveEnv {
+        activate_file: project_dir.join(ACTIVATE_TOML),
+        env,
+    })
+}
+
+impl ActiveEnv {
+    /// The `activate.toml` file the environment was activated from.
+    pub fn activate_file(&self) -> &Path {
+        &self.activate_file
+    }
+
+    pub fn vars(&self) -> &HashMap<String, String> {
+        &self.env
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.env.get(key).map(|value| value.as_str())
+    }
+
+    /// Parses the env variable `key` as `T`.
+    pub fn get<T>(&self, key: &str) -> Result<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let value = self.get_str(key).ok_or_else(|| Error::MissingVariable {
+            key: key.to_string(),
+            activate_file: self.activate_file.clone(),
+        })?;
+        value.parse().map_err(|err: T::Err| Error::InvalidValue {
+            key: key.to_string(),
+            // the active files do not record which values are secret
+            value: None,
+            activate_file: self.activate_file.clone(),
+            message: err.to_string(),
+        })
+    }
+
+    /// The `cargo:` build script directives emitted by [emit_cargo_env].
+    pub fn cargo_directives(&self, keys: Option<&[&str]>) -> Result<Vec<String>> {
+        let project_dir = self.activate_file.parent().unwrap();
+        let activate_dir = project_dir.join(ACTIVATE_DIR);
+        let watched = [
+            self.activate_file.clone(),
+            activate_dir
+                .join(ACTIVATE_ACTIVE_DIR)
+                .join(ALL_ENV_JSON_FILE),
+            activate_dir.join(ACTIVATE_STATE_DIR).join(STATE_ENV_FILE),
+        ];
+        let mut directives = watched
+            .iter()
+            .map(|file| format!("cargo:rerun-if-changed={}", file.display()))
+            .collect::<Vec<_>>();
+
+        let mut keys = match keys {
+            Some(keys) => keys.to_vec(),
+            None => self.env.keys().map(|key| key.as_str()).collect(),
+        };
+        keys.sort();
+        for key in keys {
+            let value = self.get_str(key).ok_or_else(|| Error::MissingVariable {
+                key: key.to_string(),
+                activate_file: self.activate_file.clone(),
+            })?;
+            if value.contains(['\n', '\r']) {
+                return Err(Error::InvalidValue {
+                    key: key.to_string(),
+                    value: None,
+                    activate_file: self.activate_file.clone(),
+                    message: "Values with line breaks can not be set with `cargo:rustc-env`."
+                        .to_string(),
+                });
+            }
+            directives.push(format!("cargo:rustc-env={key}={value}"));
+        }
+        Ok(directives)
+    }
+
+    /// Sets every env variable in the environment of the current process.
+    pub fn apply(&self) {
+        for (key, value) in self.env.iter() {
+            std::env::set_var(key, value);
+        }
+    }
+}
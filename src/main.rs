@@ -1,10 +1,11 @@
-use clap::Parser;
-use serde::{Deserialize, Serialize};
+use activate::{
+    ActivateOptions, CollisionStrategy, Error, Issue, NewAndOldEnv, Workspace, ACTIVATE_TOML,
+};
+use clap::{Parser, Subcommand};
 use std::{
-    collections::HashMap,
-    fmt::Debug,
-    fs::{self, File},
-    io::Write,
+    collections::HashSet,
+    error::Error as _,
+    io::{BufRead, IsTerminal, Write},
     path::{Path, PathBuf},
 };
 
@@ -13,11 +14,14 @@ use std::{
 A tool to manage environment-specific configurations. Simplifying working across various settings like Development, Testing, Production, etc.
 "#)]
 struct ActivateArgs {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Name of the environment to activate. If not provided, any active environment will be deactivated.
     env_name: Option<String>,
 
     /// The path to the directory containing the `activate.toml` file.
-    #[arg(short, long, default_value = ".")]
+    #[arg(short, long, default_value = ".", global = true)]
     path: PathBuf,
 
     /// If provided, the command to unset the old env variables and load the new env will not be sent to std out.
@@ -26,571 +30,256 @@ struct ActivateArgs {
 
     /// If provided, will activate the environment in the current directory and all subdirectories. Ignores files
     /// specified in `.gitignore` and hidden files.
-    #[arg(short, default_value = "false")]
+    #[arg(short, default_value = "false", global = true)]
     descendants: bool,
 
-    /// Name of the configmap to create.
-    #[arg(long, default_value = "activate")]
-    configmap_name: String,
+    /// Name of the configmap to create. Overrides the `[<env>.k8s.configmap]` name, which defaults to `activate`.
+    #[arg(long)]
+    configmap_name: Option<String>,
+
+    /// How to resolve an environment variable defined by more than one project when merging descendants into their
+    /// ancestors with the `-d` flag. Only the merged outputs are affected.
+    #[arg(long, value_enum, default_value_t = CollisionStrategy::Error)]
+    collision: CollisionStrategy,
+
+    /// If provided, links that were changed since they were activated, e.g. symlinks that now point somewhere else,
+    /// are removed instead of being left in place with a warning.
+    #[arg(long, default_value = "false")]
+    force: bool,
+
+    /// If provided, protected environments are activated without asking for their confirmation phrase.
+    #[arg(short, long, default_value = "false")]
+    yes: bool,
 }
 
-const ACTIVATE_TOML: &'static str = "activate.toml";
-const ACTIVATE_DIR: &'static str = ".activate";
-const ACTIVATE_STATE_DIR: &'static str = ".state";
-const ACTIVATE_ACTIVE_DIR: &'static str = "active";
-const STATE_ENV_FILE: &'static str = "env.json";
-const ALL_ENV_FILE: &'static str = ".env";
-const ALL_ENV_JSON_FILE: &'static str = "env.json";
-const ALL_ENV_CONFIGMAP_FILE: &'static str = "configmap";
-const STATE_LINKS_FILE: &'static str = "links.toml";
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Inspects the `.activate` directory of every project, explains each inconsistency and offers to fix it.
+    Doctor {
+        /// If provided, every inconsistency is fixed without asking.
+        #[arg(long, default_value = "false")]
+        fix: bool,
+    },
+    /// Shows the active environment of every project, and when it expires. Expired environments are deactivated.
+    Status,
+    /// Deactivates expired environments, printing the commands to unset their env variables. Meant to run before each
+    /// shell prompt, e.g. `PROMPT_COMMAND='eval "$(activate hook)"'`.
+    Hook,
+}
 
 fn main() {
     let args: ActivateArgs = ActivateArgs::parse();
 
     let ActivateArgs {
+        command,
         env_name: selected_env,
         path,
         silent,
         descendants,
         configmap_name,
+        collision,
+        force,
+        yes,
     } = args;
 
-    let activate_file = path.join(ACTIVATE_TOML);
-    if !activate_file.exists() {
-        exit(&format!(
-            "No `{}` file found in the current directory.",
-            ACTIVATE_TOML
-        ));
-    }
-
-    let mut envs = Vec::<NewAndOldEnv>::new();
-    if descendants {
-        let (tx, rx) = crossbeam_channel::unbounded::<NewAndOldEnv>();
-
-        ignore::WalkBuilder::new(".")
-            .hidden(true)
-            .git_ignore(true)
-            .git_global(false)
-            .git_exclude(false)
-            .parents(true)
-            .threads(num_cpus::get())
-            .build_parallel()
-            .run(|| {
-                let tx = tx.clone();
-                let selected_env = selected_env.clone();
-                Box::new(move |result| {
-                    let entry = result.exit("Could not get entry.");
-                    let path = entry.path();
-                    if path.is_dir() {
-                        let activate_file = path.join(ACTIVATE_TOML);
-                        if activate_file.exists() {
-                            tx.send(activate(&activate_file, selected_env.clone()))
-                                .exit("Could not send output.");
-                        }
-                    }
-                    ignore::WalkState::Continue
-                })
-            });
-
-        drop(tx);
-        for r in rx {
-            envs.push(r);
+    let options = ActivateOptions {
+        collision,
+        configmap_name,
+        force,
+        confirmed: yes,
+    };
+    match command {
+        Some(Command::Doctor { fix }) => match doctor(&path, descendants, fix) {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(err) => exit(err),
+        },
+        Some(Command::Status) => {
+            if let Err(err) = status(&path, descendants, &options) {
+                exit(err);
+            }
+            return;
         }
-    } else {
-        envs.push(activate(&activate_file, selected_env));
+        Some(Command::Hook) => {
+            match hook(&path, descendants, &options) {
+                Ok(Some((project_dir, envs))) => print_eval(&envs, &project_dir),
+                Ok(None) => {}
+                Err(err) => exit(err),
+            }
+            return;
+        }
+        None => {}
     }
 
-    let envs = create_env_hierarchy(&envs)
-        .into_iter()
-        .map(|(env, sub_envs)| {
-            sub_envs.into_iter().fold(
-                NewAndOldEnv {
-                    activate_toml_file_directory: env.activate_toml_file_directory.clone(),
-                    old_env: env.old_env.clone(),
-                    new_env: env.new_env.clone(),
-                },
-                |mut acc, env| {
-                    for key in env.new_env.keys() {
-                        if acc.new_env.contains_key(key) {
-                            exit(&format!(r#"Could not fully activate environment. Environment variable collision.
-
-`{key}` is defined in `{}` and `{}`"#, acc.activate_toml_file_directory.join(ACTIVATE_TOML).display(), env.activate_toml_file_directory.join(ACTIVATE_TOML).display(),));                        
-                        }
-                    }
-                    acc.old_env.extend(env.old_env.clone());
-                    acc.new_env.extend(env.new_env.clone());
-                    acc
-                },
-            )
-        })
-        .collect::<Vec<_>>();
-
-    for env in envs.iter() {
-        let NewAndOldEnv {
-            activate_toml_file_directory,
-            old_env,
-            new_env,
-        } = env;
-
-        let active_dir = activate_toml_file_directory
-            .join(ACTIVATE_DIR)
-            .join(ACTIVATE_ACTIVE_DIR);
-
-        let json_env_file_data = serde_json::to_string_pretty(&new_env)
-            .expect("Could not serialize environment variables to json.");
-        fs::write(active_dir.join(ALL_ENV_JSON_FILE), json_env_file_data)
-            .exit(format!("Could not write to `{}` file.", ALL_ENV_JSON_FILE).as_str());
-
-        let mut old_env = old_env.into_iter().collect::<Vec<_>>();
-        old_env.sort_by(|e1, e2| e1.0.cmp(e2.0));
-        let mut new_env = new_env.into_iter().collect::<Vec<_>>();
-        new_env.sort_by(|e1, e2| e1.0.cmp(e2.0));
-
-        let env_file_data = new_env.iter().fold(
-            r#"# Generated - managed by `activate`.
-
-"#
-            .to_string(),
-            |mut s, (k, v)| {
-                s.push_str(&format!("{}={}\n", k, v));
-                s
-            },
-        );
-        fs::write(active_dir.join(ALL_ENV_FILE), env_file_data)
-            .exit(format!("Could not write to `{}` file.", ALL_ENV_FILE).as_str());
-
-        let configmap_file_data = new_env.iter().fold(
-            format!(
-                r#"# Generated - managed by `activate`.
-
-apiVersion: v1
-kind: ConfigMap
-metadata:
-  name: {}
-data:
-"#,
-                configmap_name
-            ),
-            |mut s, (k, v)| {
-                s.push_str(&format!("  {}: \"{}\"\n", k, v));
-                s
-            },
-        );
-        fs::write(active_dir.join(ALL_ENV_CONFIGMAP_FILE), configmap_file_data)
-            .exit(format!("Could not write to `{}` file.", ALL_ENV_CONFIGMAP_FILE).as_str());
-    }
+    let envs = match run(&path, descendants, selected_env.as_deref(), &options) {
+        Ok(envs) => envs,
+        Err(err) => exit(err),
+    };
 
-    // eval output
     if !silent {
-        let this_env = envs
-            .iter()
-            .find(|env| env.activate_toml_file_directory == path)
-            .unwrap();
-
-        let mut output = Vec::<String>::new();
-        let mut keys: Vec<&String> = this_env.old_env.keys().collect();
-        keys.sort();
-        for key in keys {
-            output.push(format!("unset {}", key));
-        }
-        let mut keys: Vec<&String> = this_env.new_env.keys().collect();
-        keys.sort();
-        for key in keys {
-            if let Some(value) = this_env.new_env.get(key) {
-                output.push(format!("export {}={}", key, value));
-            }
-        }
-        if !output.is_empty() {
-            let output = output.join("\n");
-            println!("{}", output);
-        }
+        print_eval(&envs, &path);
     }
 }
 
-/// Creates a hierarchy of envs
-fn create_env_hierarchy<'a>(
-    envs: &'a [NewAndOldEnv],
-) -> Vec<(&'a NewAndOldEnv, Vec<&'a NewAndOldEnv>)> {
-    let mut hierarchy = Vec::new();
-    for env1 in envs {
-        let mut subs = Vec::new();
-        for env2 in envs {
-            if env1.activate_toml_file_directory == env2.activate_toml_file_directory {
-                continue;
-            }
-            if env2
-                .activate_toml_file_directory
-                .starts_with(&env1.activate_toml_file_directory)
-            {
-                subs.push(env2);
-            }
-        }
-        hierarchy.push((env1, subs));
+/// Prints the commands to unset the old env variables and export the new ones of the project in `path`, to be
+/// evaluated by the shell.
+fn print_eval(envs: &[NewAndOldEnv], path: &Path) {
+    let this_env = envs
+        .iter()
+        .find(|env| env.activate_toml_file_directory == path)
+        .unwrap();
+
+    let mut output = Vec::<String>::new();
+    let mut keys: Vec<&String> = this_env.old_env.keys().collect();
+    keys.sort();
+    for key in keys {
+        output.push(format!("unset {}", key));
     }
-    hierarchy
-}
-
-struct NewAndOldEnv {
-    activate_toml_file_directory: PathBuf,
-    old_env: HashMap<String, String>,
-    new_env: HashMap<String, String>,
-}
-
-/// Sources parameters and activates the environment. Returns a strings to set the environment variables if `eval` is true.
-fn activate(activate_file: &Path, selected_env: Option<String>) -> NewAndOldEnv {
-    let contents = fs::read_to_string(&activate_file)
-        .exit(&format!("Could not read `{}` file.", ACTIVATE_TOML));
-    let toml: Environments =
-        toml::from_str(&contents).exit(&format!("Could not parse `{}`.", ACTIVATE_TOML));
-
-    let current_dir = activate_file.parent().unwrap();
-    let activate_dir = current_dir.join(ACTIVATE_DIR);
-    let state_dir = activate_dir.join(ACTIVATE_STATE_DIR);
-    let active_dir = activate_dir.join(ACTIVATE_ACTIVE_DIR);
-    let env_file = state_dir.join(STATE_ENV_FILE);
-    let links_file = state_dir.join(STATE_LINKS_FILE);
-
-    ensure_active_files_exist(&active_dir);
-
-    let new_env: Option<HashMap<String, String>>;
-    let old_active_env;
-    if let Some(selected_env) = &selected_env {
-        let EnvironmentData { env, links } = toml
-            .0
-            .exit(&format!("No environments found in `{}`.", ACTIVATE_TOML))
-            .remove(selected_env)
-            .exit(&format!("'{}' is not a valid environment", &selected_env));
-
-        if state_dir.exists() {
-            old_active_env = decativate_current(&env_file, &links_file, &current_dir);
-        } else {
-            old_active_env = None;
-            fs::create_dir_all(&state_dir).exit(&format!(
-                "Could not create `{}` directory.",
-                state_dir.to_string_lossy()
-            ));
-            create_gitignore_file(&activate_dir);
-            create_readmes(&activate_dir);
+    let mut keys: Vec<&String> = this_env.new_env.keys().collect();
+    keys.sort();
+    for key in keys {
+        if let Some(value) = this_env.new_env.get(key) {
+            output.push(format!("export {}={}", key, value));
         }
-
-        activate_new(&env, &env_file, &links, &links_file, &current_dir);
-        new_env = env;
-    } else {
-        if state_dir.exists() {
-            old_active_env = decativate_current(&env_file, &links_file, &current_dir);
-        } else {
-            old_active_env = None;
-        }
-        new_env = None;
     }
-
-    NewAndOldEnv {
-        activate_toml_file_directory: activate_file.parent().unwrap().to_path_buf(),
-        old_env: old_active_env.map(|e| e.0).flatten().unwrap_or_default(),
-        new_env: new_env.unwrap_or_default(),
+    if !output.is_empty() {
+        let output = output.join("\n");
+        println!("{}", output);
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct Environments(Option<HashMap<String, EnvironmentData>>);
-
-#[derive(Debug, Serialize, Deserialize)]
-struct EnvironmentData {
-    env: Option<HashMap<String, String>>,
-    links: Option<HashMap<String, String>>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct ActiveEnvironmentEnv(Option<HashMap<String, String>>);
-
-#[derive(Debug, Serialize, Deserialize)]
-struct ActiveEnvironmentLinks(Option<HashMap<String, String>>);
-
-fn decativate_current(
-    current_env_file: &Path,
-    current_links_file: &Path,
-    current_dir: &Path,
-) -> Option<ActiveEnvironmentEnv> {
-    let old_env_vars;
-    if current_env_file.exists() {
-        old_env_vars = Some(remove_env(current_env_file));
-    } else {
-        old_env_vars = None;
-    }
-    if current_links_file.exists() {
-        remove_links(current_links_file, current_dir);
+fn run(
+    path: &Path,
+    descendants: bool,
+    selected_env: Option<&str>,
+    options: &ActivateOptions,
+) -> activate::Result<Vec<NewAndOldEnv>> {
+    let workspace = Workspace::discover(path, descendants)?;
+    let plan = workspace.resolve(selected_env)?;
+    let phrases = plan.confirmation_phrases();
+    if phrases.is_empty() || options.confirmed || !std::io::stdin().is_terminal() {
+        return plan.apply(options);
     }
-
-    old_env_vars
+    let confirmed = phrases.iter().all(|phrase| confirm_phrase(phrase));
+    plan.apply(&ActivateOptions {
+        confirmed,
+        ..options.clone()
+    })
 }
 
-/// Activates the new environment.
-fn activate_new(
-    env: &Option<HashMap<String, String>>,
-    env_file: &Path,
-    links: &Option<HashMap<String, String>>,
-    links_file: &Path,
-    current_dir: &Path,
-) {
-    if let Some(env) = env {
-        add_env(env, env_file);
+/// Asks to type `phrase` to activate a protected environment.
+fn confirm_phrase(phrase: &str) -> bool {
+    eprint!(
+        "The environment is protected. Type `{}` to activate it: ",
+        phrase
+    );
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    if std::io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
     }
-    if let Some(links) = links {
-        add_links(links, links_file, current_dir);
-    }
-}
-
-//************************************************************************//
-
-fn add_env(env_vars: &HashMap<String, String>, env_file: &Path) {
-    let mut env_file = File::options()
-        .create(true)
-        .append(true)
-        .open(&env_file)
-        .exit(&format!("Could not create `{}` file.", STATE_ENV_FILE));
-    env_file
-        .write(
-            serde_json::to_string(&ActiveEnvironmentEnv(Some(env_vars.clone())))
-                .exit("Could not serialize environment variables")
-                .as_bytes(),
-        )
-        .exit(&format!("Could not write to `{}` file.", STATE_ENV_FILE));
+    answer.trim() == phrase
 }
 
-fn remove_env(current_env_file: &Path) -> ActiveEnvironmentEnv {
-    let env_string = fs::read_to_string(&current_env_file)
-        .exit(&format!("Could not read `{}` file.", STATE_ENV_FILE));
-    let old_env_vars_result = serde_json::from_str::<ActiveEnvironmentEnv>(&env_string);
-    let old_env_vars = match old_env_vars_result {
-        Ok(ok) => ok,
-        Err(err) => {
-            if err.is_eof() {
-                ActiveEnvironmentEnv(None)
-            } else {
-                exit(&format!(
-                    "Could not parse `{}` file. Error was: {}",
-                    STATE_ENV_FILE, err
-                ));
-            }
-        }
-    };
-
-    fs::remove_file(&current_env_file).exit(&format!(
-        "Could not remove `{}` file. Environemnt is still active.",
-        STATE_ENV_FILE
-    ));
-
-    old_env_vars
-}
-
-//************************************************************************//
-
-fn create_gitignore_file(activate_dir: &Path) {
-    fs::write(
-        &activate_dir.join(".gitignore"),
-        &format!(
-            "{}/\n{}/\n{}",
-            ACTIVATE_STATE_DIR, ACTIVATE_ACTIVE_DIR, "README.md"
-        ),
-    )
-    .exit("Could not create `.gitignore` file.");
-}
-
-fn create_readmes(activate_dir: &Path) {
-    let readme = activate_dir.join("README.md");
-    fs::write(
-        &readme,
-        format!(
-            r#"This directory stores data for the currently active environment.
-Files can freely be added to this directly, but do not change the `{}` directory.
-The `{}` directory can be modified, but note changes may be overwritten."#,
-            ACTIVATE_STATE_DIR, ACTIVATE_ACTIVE_DIR
-        ),
-    )
-    .exit(&format!("Could not create `{}` file.", readme.display()));
-    let readme = activate_dir.join(ACTIVATE_STATE_DIR).join("README.md");
-    fs::write(
-        &readme,
-        format!(
-            r#"This directory should not be modified. It stores the links and env variables
-activated in the current environment that are from this `{}` file"#, //todo check to make sure
-            ACTIVATE_TOML
-        ),
-    )
-    .exit(&format!("Could not create `{}` file.", readme.display()));
-    let readme = activate_dir.join(ACTIVATE_ACTIVE_DIR).join("README.md");
-    fs::write(
-        &readme,
-        format!(
-            r#"This directory contains the activated cofig, such env variables, that
-are activated in the current environment and are from this `{}` file or any descendants if 
-activated with the `-d` flags. These files are safe to consumed"#,
-            ACTIVATE_TOML
-        ),
-    )
-    .exit(&format!("Could not create `{}` file.", readme.display()));
-}
-
-fn ensure_active_files_exist(active_dir: &Path) {
-    if !active_dir.exists() {
-        fs::create_dir_all(&active_dir).exit(&format!(
-            "Could not create `{}` directory.",
-            active_dir.to_string_lossy()
-        ));
-    }
-    for active_file in [ALL_ENV_FILE, ALL_ENV_CONFIGMAP_FILE, ALL_ENV_JSON_FILE] {
-        let full_path = active_dir.join(active_file);
-        if !full_path.exists() {
-            fs::write(&full_path, "").exit(&format!("Could not create `{}` file.", active_file));
+fn status(path: &Path, descendants: bool, options: &ActivateOptions) -> activate::Result<()> {
+    let workspace = Workspace::discover(path, descendants)?;
+    workspace.expire(options)?;
+    for (project, state) in workspace.state()? {
+        match (state.env_name, state.expires_at) {
+            (Some(env_name), Some(expires_at)) => println!(
+                "{}: {} (expires at {})",
+                project.dir().display(),
+                env_name,
+                humantime::format_rfc3339_seconds(expires_at)
+            ),
+            (Some(env_name), None) => println!("{}: {}", project.dir().display(), env_name),
+            (None, _) => println!("{}: no active environment", project.dir().display()),
         }
     }
+    Ok(())
 }
 
-//************************************************************************//
-
-fn add_links(links: &HashMap<String, String>, current_links_file: &Path, current_dir: &Path) {
-    let mut links_file = File::options()
-        .create(true)
-        .append(true)
-        .open(current_links_file)
-        .exit(&format!("Could not open `{}` file.", STATE_LINKS_FILE));
-    for (key, value) in links {
-        let source = Path::new(&value);
-        if source.starts_with("./") || source.starts_with("../") {
-            exit(&format!("The source `{}` should not start with `./` or `../`. The source is relative to the `activate.toml` directory and below.", source.to_string_lossy()));
-        }
-        let mut source = current_dir.join(source);
-        if source.starts_with("./") {
-            source = source.strip_prefix("./").unwrap().to_path_buf();
-        }
-        if !source.exists() {
-            exit(&format!(
-                "The source `{}` does not exist.",
-                source.to_string_lossy()
-            ));
-        }
-        let target = Path::new(&key);
-        if target.starts_with("./") || target.starts_with("../") {
-            exit(&format!("The target `{}` should not start with `./` or `../`. The target is relative to the `activate.toml` directory and below.", target.to_string_lossy()));
-        }
-        let mut target = current_dir.join(target);
-        if target.starts_with("./") {
-            target = target.strip_prefix("./").unwrap().to_path_buf();
-        }
-        if target.exists() {
-            exit(&format!(
-                "The target `{}` already exists.",
-                target.to_string_lossy()
-            ));
-        }
-        if target.is_symlink() {
-            exit(&format!(
-                "The link `{}` already exists.",
-                target.to_string_lossy()
-            ));
+/// Deactivates expired environments of the nearest project, searching from `path` up. Returns the project's directory
+/// with the envs, if any expired. Outside of a project, nothing is done.
+fn hook(
+    path: &Path,
+    descendants: bool,
+    options: &ActivateOptions,
+) -> activate::Result<Option<(PathBuf, Vec<NewAndOldEnv>)>> {
+    let project_dir = if path.join(ACTIVATE_TOML).exists() {
+        path.to_path_buf()
+    } else {
+        let start = path.canonicalize().map_err(|source| Error::Io {
+            context: format!("Could not resolve `{}`.", path.display()),
+            source,
+        })?;
+        match start
+            .ancestors()
+            .find(|dir| dir.join(ACTIVATE_TOML).exists())
+        {
+            Some(project_dir) => project_dir.to_path_buf(),
+            None => return Ok(None),
         }
-        links_file
-            .write_all(&format!("\"{}\"=\"{}\"\n", key, value).as_bytes())
-            .exit(&format!(
-                "Could not write to `{}` file. In directory `{}`.",
-                STATE_LINKS_FILE,
-                current_dir.to_string_lossy()
-            ));
-        let depth_adjustment = PathBuf::from(key)
-            .components()
-            .skip(1)
-            .fold(PathBuf::new(), |p, _| p.join(".."));
-        let link_path = depth_adjustment.join(value);
-        // #[cfg(windows)]
-        // {
-        //     let metadata = fs::symlink_metadata(&value)
-        //         .exit(&format!("Could not get metadata for `{}`.", &key));
-        //     if metadata.is_dir() {
-        //         std::os::windows::fs::symlink_any(link_path, target).exit(&format!(
-        //             "Could not link entity `{}` to `{}`, in directory `{}`.",
-        //             &key,
-        //             &value,
-        //             current_dir.to_string_lossy()
-        //         ));
-        //     } else {
-        //         std::os::windows::fs::symlink_file(link_path, target).exit(&format!(
-        //             "Could not link entity `{}` to `{}`, in directory `{}`.",
-        //             &key,
-        //             &value,
-        //             current_dir.to_string_lossy()
-        //         ));
-        //     }
-        // }
-        #[cfg(unix)]
-        std::os::unix::fs::symlink(link_path, target).exit(&format!(
-            "Could not link entity `{}` to `{}`, in directory `{}`.",
-            &key,
-            &value,
-            current_dir.to_string_lossy()
-        ));
-    }
+    };
+    let workspace = Workspace::discover(&project_dir, descendants)?;
+    Ok(workspace.expire(options)?.map(|envs| (project_dir, envs)))
 }
 
-fn remove_links(current_links_file: &Path, current_dir: &Path) {
-    let links_string = fs::read_to_string(&current_links_file)
-        .exit(&format!("Could not read `{}` file.", STATE_LINKS_FILE));
-    let links = toml::from_str::<ActiveEnvironmentLinks>(&links_string)
-        .exit(&format!("Could not parse `{}` file.", STATE_LINKS_FILE));
-    if let Some(links) = links.0 {
-        for (key, _value) in links {
-            let target = current_dir.join(&key);
-            if target.exists() {
-                if target.is_symlink() {
-                    fs::remove_file(&target).exit(&format!(
-                        "Could not remove link `{}`.",
-                        target.to_string_lossy()
-                    ));
+/// Explains every inconsistency and fixes it, if `fix` is set or confirmed on a terminal. Fixing an inconsistency may
+/// uncover others, so the projects are inspected again until nothing more is fixed. Returns whether no inconsistencies
+/// are left.
+fn doctor(path: &Path, descendants: bool, fix: bool) -> activate::Result<bool> {
+    let workspace = Workspace::discover(path, descendants)?;
+    let interactive = !fix && std::io::stdin().is_terminal();
+    // Issues that were fixed or declined are not offered again.
+    let mut handled = HashSet::new();
+    loop {
+        let mut fixed = false;
+        let mut left = false;
+        for (project, issues) in workspace.diagnose()? {
+            for issue in issues {
+                if !handled.insert((project.dir().to_path_buf(), issue.clone())) {
+                    left = true;
+                    continue;
+                }
+                println!("{}: {}", project.dir().display(), issue);
+                if fix || (interactive && confirm(&issue)) {
+                    project.repair(&issue)?;
+                    println!("  Fixed: {}", issue.fix());
+                    fixed = true;
                 } else {
-                    exit(&format!("The existing link `{}` is not a symlink. Therefore it will not be removed.", target.to_string_lossy()));
+                    println!("  Fix: {}", issue.fix());
+                    left = true;
                 }
             }
         }
+        if !fixed {
+            if handled.is_empty() {
+                println!("No inconsistencies found.");
+            }
+            return Ok(!left);
+        }
     }
-
-    fs::remove_file(&current_links_file).exit(&format!(
-        "Could not remove `{}` file. Links are still active.",
-        STATE_LINKS_FILE
-    ));
-}
-
-//************************************************************************//
-
-fn exit(message: &str) -> ! {
-    exit_handler(message, std::backtrace::Backtrace::force_capture());
-}
-
-fn exit_handler<E: Debug>(message: &str, _error: E) -> ! {
-    eprintln!("Error: {}", message);
-    #[cfg(debug_assertions)]
-    {
-        eprintln!("{:?}", _error);
-    }
-    std::process::exit(1);
-}
-
-trait Exit<T> {
-    fn exit(self, exit_message: &str) -> T;
 }
 
-impl<T, U: Debug> Exit<T> for Result<T, U> {
-    fn exit(self, exit_message: &str) -> T {
-        match self {
-            Ok(ok) => ok,
-            Err(err) => exit_handler(exit_message, err),
-        }
+/// Asks whether to apply the fix for `issue`.
+fn confirm(issue: &Issue) -> bool {
+    eprint!("  {} Fix? [y/N] ", issue.fix());
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    if std::io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
     }
+    matches!(answer.trim(), "y" | "Y" | "yes")
 }
 
-impl<T> Exit<T> for Option<T> {
-    fn exit(self, exit_message: &str) -> T {
-        match self {
-            Some(v) => v,
-            None => exit_handler(exit_message, std::backtrace::Backtrace::force_capture()),
-        }
+/// Prints `err` with its chain of causes and exits with its exit code.
+fn exit(err: Error) -> ! {
+    eprintln!("Error: {}", err);
+    let mut source = err.source();
+    while let Some(cause) = source {
+        eprintln!("  Caused by: {}", cause);
+        source = cause.source();
     }
+    std::process::exit(err.exit_code());
 }
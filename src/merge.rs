@@ -0,0 +1,266 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+
+use crate::{
+    error::{Error, Result},
+    output::{KubernetesSettings, OutputSettings},
+    ACTIVATE_TOML, SECRET_MASK,
+};
+
+/// How to resolve an environment variable defined by more than one project, when merging descendants into their
+/// ancestors.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CollisionStrategy {
+    /// Fail the activation.
+    #[default]
+    Error,
+    /// The value from the more deeply nested project is used.
+    ChildWins,
+    /// The value from the less deeply nested project is used.
+    ParentWins,
+    /// Every descendant variable is namespaced with the descendant's `prefix` (defaults to its directory name,
+    /// e.g. `API_PORT`).
+    Prefix,
+}
+
+/// Merges the envs of each project's descendants into it, resolving collisions with `strategy`. Projects are sorted by
+/// path, so each project directly precedes its descendants, and merged bottom-up. Collisions between projects that are
+/// not ancestors of one another are always an error, unless they are avoided with `prefix`.
+pub(crate) fn merge_hierarchy(
+    mut envs: Vec<NewAndOldEnv>,
+    strategy: CollisionStrategy,
+) -> Result<Vec<NewAndOldEnv>> {
+    envs.sort_by(|e1, e2| {
+        e1.activate_toml_file_directory
+            .cmp(&e2.activate_toml_file_directory)
+    });
+
+    let mut children = vec![Vec::new(); envs.len()];
+    let mut ancestors: Vec<usize> = Vec::new();
+    for (index, env) in envs.iter().enumerate() {
+        while let Some(&ancestor) = ancestors.last() {
+            if env
+                .activate_toml_file_directory
+                .starts_with(&envs[ancestor].activate_toml_file_directory)
+            {
+                children[ancestor].push(index);
+                break;
+            }
+            ancestors.pop();
+        }
+        ancestors.push(index);
+    }
+
+    let mut merged: Vec<MergedEnv> = Vec::with_capacity(envs.len());
+    for index in (0..envs.len()).rev() {
+        let mut acc = MergedEnv::new(index, &envs[index]);
+        for &child in children[index].iter() {
+            // children have a greater index, so are already merged
+            acc.merge(child, &merged[envs.len() - 1 - child], &envs, strategy)?;
+        }
+        merged.push(acc);
+    }
+    merged.reverse();
+
+    Ok(envs
+        .into_iter()
+        .zip(merged)
+        .map(|(env, merged)| NewAndOldEnv {
+            k8s: KubernetesSettings {
+                secret_keys: merged
+                    .new_env
+                    .iter()
+                    .filter(|(_, var)| var.k8s_secret)
+                    .map(|(key, _)| key.clone())
+                    .chain(env.k8s.secret_keys.iter().cloned())
+                    .collect(),
+                ..env.k8s.clone()
+            },
+            secrets: merged
+                .new_env
+                .iter()
+                .filter(|(_, var)| var.secret)
+                .map(|(key, _)| key.clone())
+                .collect(),
+            old_env: merged
+                .old_env
+                .into_iter()
+                .map(|(key, var)| (key, var.value))
+                .collect(),
+            new_env: merged
+                .new_env
+                .into_iter()
+                .map(|(key, var)| (key, var.value))
+                .collect(),
+            ..env
+        })
+        .collect())
+}
+
+/// The env of a project merged with the envs of its descendants.
+struct MergedEnv {
+    old_env: HashMap<String, MergedVar>,
+    new_env: HashMap<String, MergedVar>,
+}
+
+#[derive(Clone)]
+struct MergedVar {
+    value: String,
+    /// The key in the `activate.toml` file of the project it was defined in.
+    key: String,
+    /// The index of the project it was defined in.
+    origin: usize,
+    secret: bool,
+    /// Whether it is in the `[<env>.k8s] secret_keys` of the project it was defined in.
+    k8s_secret: bool,
+}
+
+impl MergedEnv {
+    fn new(index: usize, env: &NewAndOldEnv) -> MergedEnv {
+        let vars = |vars: &HashMap<String, String>| {
+            vars.iter()
+                .map(|(key, value)| {
+                    let var = MergedVar {
+                        value: value.clone(),
+                        key: key.clone(),
+                        origin: index,
+                        secret: env.secrets.contains(key),
+                        k8s_secret: env.k8s.secret_keys.contains(key),
+                    };
+                    (key.clone(), var)
+                })
+                .collect()
+        };
+        MergedEnv {
+            old_env: vars(&env.old_env),
+            new_env: vars(&env.new_env),
+        }
+    }
+
+    /// Merges the already merged env of the child at `child_index` into this env.
+    fn merge(
+        &mut self,
+        child_index: usize,
+        child: &MergedEnv,
+        envs: &[NewAndOldEnv],
+        strategy: CollisionStrategy,
+    ) -> Result<()> {
+        // only the child's own variables still need namespacing, its descendants' already are
+        let child_prefix = |strategy: CollisionStrategy| match strategy {
+            CollisionStrategy::Prefix => envs[child_index].prefix(),
+            _ => String::new(),
+        };
+        let merged_key = |key: &String, var: &MergedVar, prefix: &str| {
+            if var.origin == child_index {
+                format!("{}{}", prefix, var.key)
+            } else {
+                key.clone()
+            }
+        };
+
+        // the old variables were namespaced by the strategy of the child's previous activation
+        let old_prefix = child_prefix(envs[child_index].old_collision.unwrap_or(strategy));
+        for (key, var) in child.old_env.iter() {
+            self.old_env
+                .insert(merged_key(key, var, &old_prefix), var.clone());
+        }
+        let child_prefix = child_prefix(strategy);
+        for (key, var) in child.new_env.iter() {
+            let origin = &envs[var.origin];
+            // an inherited variable already merged is the ancestor's own definition
+            if origin.inherited.contains(&var.key) && self.new_env.contains_key(&var.key) {
+                continue;
+            }
+            let key = merged_key(key, var, &child_prefix);
+            if let Some(existing) = self.new_env.get(&key) {
+                let existing = &envs[existing.origin];
+                let is_ancestor = origin
+                    .activate_toml_file_directory
+                    .starts_with(&existing.activate_toml_file_directory);
+                match strategy {
+                    CollisionStrategy::ChildWins if is_ancestor => {}
+                    CollisionStrategy::ParentWins if is_ancestor => continue,
+                    _ => {
+                        return Err(Error::Collision {
+                            key,
+                            first: existing.activate_toml_file_directory.join(ACTIVATE_TOML),
+                            second: origin.activate_toml_file_directory.join(ACTIVATE_TOML),
+                        })
+                    }
+                }
+            }
+            self.new_env.insert(key, var.clone());
+        }
+        Ok(())
+    }
+}
+
+/// The env variables of a project before and after an activation. After merging, these include the env variables of
+/// its descendants.
+#[derive(Debug, Clone)]
+pub struct NewAndOldEnv {
+    pub activate_toml_file_directory: PathBuf,
+    /// The name of the activated environment, or `None` if deactivated.
+    pub env_name: Option<String>,
+    /// The configured `prefix` of the `activate.toml` file, if any.
+    pub(crate) prefix: Option<String>,
+    /// Keys of `new_env` that were inherited from an ancestor `activate.toml` file.
+    pub(crate) inherited: HashSet<String>,
+    /// Keys of `new_env` whose values are secret.
+    pub(crate) secrets: HashSet<String>,
+    /// The `[<env>.k8s]` settings of the activated environment.
+    pub(crate) k8s: KubernetesSettings,
+    /// The `[outputs]` section of the `activate.toml` file.
+    pub(crate) outputs: OutputSettings,
+    /// The collision strategy `old_env` was merged with, if it was recorded.
+    pub(crate) old_collision: Option<CollisionStrategy>,
+    /// The env variables that were active before, to unset.
+    pub old_env: HashMap<String, String>,
+    /// The env variables that are now active, to set.
+    pub new_env: HashMap<String, String>,
+}
+
+impl NewAndOldEnv {
+    /// Whether the value of `key` in `new_env` is secret.
+    pub fn is_secret(&self, key: &str) -> bool {
+        self.secrets.contains(key)
+    }
+
+    /// The value of `key` in `new_env` for display, with secret values masked.
+    pub fn display_value(&self, key: &str) -> Option<&str> {
+        if self.is_secret(key) {
+            return self.new_env.get(key).map(|_| SECRET_MASK);
+        }
+        self.new_env.get(key).map(|value| value.as_str())
+    }
+
+    /// The prefix used to namespace this env's variables when merged into an ancestor. Defaults to the directory name,
+    /// e.g. `API_` for `services/api`.
+    fn prefix(&self) -> String {
+        if let Some(prefix) = &self.prefix {
+            return prefix.clone();
+        }
+        let dir_name = self
+            .activate_toml_file_directory
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        let mut prefix = dir_name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        prefix.push('_');
+        prefix
+    }
+}
@@ -0,0 +1,765 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::{Context, Error, Result},
+    merge::NewAndOldEnv,
+    state::{is_within, read_templates, restrict_permissions, write_templates, ActiveTemplate},
+    template, ACTIVATE_ACTIVE_DIR, ACTIVATE_DIR, ACTIVATE_STATE_DIR, ACTIVATE_TOML,
+    ALL_ENV_CONFIGMAP_FILE, ALL_ENV_DIRENV_FILE, ALL_ENV_FILE, ALL_ENV_HELM_FILE,
+    ALL_ENV_JSON_FILE, ALL_ENV_PROPERTIES_FILE, ALL_ENV_SECRET_FILE, ALL_ENV_SYSTEMD_FILE,
+    ALL_ENV_TFVARS_FILE, STATE_OUTPUTS_FILE,
+};
+
+/// The `[outputs]` section of an `activate.toml` file. Picks which formats the active environment is written in.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputSettings {
+    pub dotenv: OutputTarget,
+    pub dotenv_flavor: DotenvFlavor,
+    pub json: OutputTarget,
+    pub configmap: OutputTarget,
+    pub secret: OutputTarget,
+    pub systemd: OutputTarget,
+    pub properties: OutputTarget,
+    pub tfvars: OutputTarget,
+    pub helm: OutputTarget,
+    pub direnv: OutputTarget,
+    /// User templates, rendered to their output path. Both are relative to the directory of the `activate.toml` file.
+    pub templates: BTreeMap<PathBuf, PathBuf>,
+}
+
+/// The rules the `.env` file is quoted and escaped by, as tools disagree on them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DotenvFlavor {
+    /// Double quoted values with escapes and `\n` for line breaks, as read by the `dotenv` crate, python-dotenv and
+    /// Docker Compose.
+    #[default]
+    Dotenv,
+    /// Double quoted values with escapes, spanning multiple lines for line breaks, as read by Docker Compose.
+    Docker,
+    /// Unquoted values, as read by `docker run --env-file`. Line breaks are not supported.
+    Plain,
+}
+
+impl DotenvFlavor {
+    fn name(&self) -> &'static str {
+        match self {
+            DotenvFlavor::Dotenv => "dotenv",
+            DotenvFlavor::Docker => "docker",
+            DotenvFlavor::Plain => "plain",
+        }
+    }
+}
+
+/// Where an output format is written.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum OutputTarget {
+    /// If true, written to the `.activate/active` directory.
+    Enabled(bool),
+    /// Written to this path, relative to the directory of the `activate.toml` file.
+    Path(PathBuf),
+}
+
+impl Default for OutputSettings {
+    fn default() -> Self {
+        OutputSettings {
+            dotenv: OutputTarget::Enabled(true),
+            dotenv_flavor: DotenvFlavor::default(),
+            json: OutputTarget::Enabled(true),
+            configmap: OutputTarget::Enabled(true),
+            secret: OutputTarget::Enabled(true),
+            systemd: OutputTarget::Enabled(false),
+            properties: OutputTarget::Enabled(false),
+            tfvars: OutputTarget::Enabled(false),
+            helm: OutputTarget::Enabled(false),
+            direnv: OutputTarget::Enabled(false),
+            templates: BTreeMap::new(),
+        }
+    }
+}
+
+/// The `[<env>.k8s]` settings of an environment, for the generated Kubernetes manifests.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct KubernetesSettings {
+    /// Env variables put in the Secret instead of the ConfigMap, in addition to those marked secret.
+    pub secret_keys: HashSet<String>,
+    pub configmap: ManifestSettings,
+    pub secret: ManifestSettings,
+}
+
+/// The metadata of a generated Kubernetes manifest.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct ManifestSettings {
+    /// Defaults to `activate` for the configmap, and to the configmap name for the secret.
+    pub name: Option<String>,
+    pub namespace: Option<String>,
+    pub labels: BTreeMap<String, String>,
+    pub annotations: BTreeMap<String, String>,
+    pub immutable: bool,
+}
+
+const DEFAULT_CONFIGMAP_NAME: &'static str = "activate";
+
+const GENERATED_HEADER: &'static str = "# Generated - managed by `activate`.\n\n";
+
+/// Creates each enabled output file that does not exist yet, empty, so it can be the source of a link.
+pub(crate) fn ensure_active_files_exist(
+    project_dir: &Path,
+    outputs: &OutputSettings,
+) -> Result<()> {
+    let outputs_file = outputs_file(project_dir);
+    let mut recorded = None;
+    for (name, target, format) in formats(outputs) {
+        match target {
+            OutputTarget::Enabled(true) => {
+                let file = active_dir(project_dir).join(format.file_name());
+                if !file.exists() {
+                    write_output(&file, "", false)?;
+                }
+            }
+            OutputTarget::Path(path) => {
+                let key = path.to_string_lossy();
+                let file = project_dir.join(path);
+                // anything else fails the activation, once its outputs are written
+                if file.exists() || file.is_symlink() || !is_within(&key, project_dir, true) {
+                    continue;
+                }
+                write_output(&file, "", false)?;
+                let recorded = match &mut recorded {
+                    Some(recorded) => recorded,
+                    None => recorded.insert(read_outputs(&outputs_file)?),
+                };
+                recorded.insert(key.into_owned(), recorded_format(name));
+                record_outputs(&outputs_file, recorded)?;
+            }
+            OutputTarget::Enabled(false) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Writes `env` in each format enabled in its `[outputs]` section and renders its templates, or removes the rendered
+/// templates if deactivated. Files containing secrets are only readable by the owner. `configmap_name` overrides the
+/// configured configmap name.
+pub(crate) fn write_active_files(env: &NewAndOldEnv, configmap_name: Option<&str>) -> Result<()> {
+    render_active_files(env, configmap_name)?.write()
+}
+
+/// Renders the files of `env` like [write_active_files], without writing them, so an activation that can not write
+/// them fails before anything is activated.
+pub(crate) fn check_active_files(env: &NewAndOldEnv, configmap_name: Option<&str>) -> Result<()> {
+    render_active_files(env, configmap_name).map(|_| ())
+}
+
+/// The files written for the env of a project, rendered and checked before any of them is written.
+struct ActiveFiles {
+    /// The files in `.activate/active`, with their contents and whether they contain secrets.
+    active: Vec<(PathBuf, String, bool)>,
+    /// The files in `.activate/active` of formats that are not written there anymore.
+    disabled: Vec<PathBuf>,
+    /// The files at a configured path, recorded in the outputs state.
+    tracked: Vec<TrackedOutput>,
+    /// The outputs state, mapping each path written before to its template, or the format written to it.
+    outputs_file: PathBuf,
+    recorded: HashMap<String, ActiveTemplate>,
+    project_dir: PathBuf,
+}
+
+/// A format written to a configured path, or a rendered `[outputs.templates]` entry.
+struct TrackedOutput {
+    /// The path, relative to the directory of the `activate.toml` file.
+    target: String,
+    /// What is recorded in the outputs state for the path.
+    active_output: ActiveTemplate,
+    is_template: bool,
+    contents: String,
+    restricted: bool,
+}
+
+/// Renders the files of `env`. Paths outside of the project, and existing files that were not written by `activate`,
+/// fail before anything is written.
+fn render_active_files(env: &NewAndOldEnv, configmap_name: Option<&str>) -> Result<ActiveFiles> {
+    let project_dir = &env.activate_toml_file_directory;
+    let activate_file = project_dir.join(ACTIVATE_TOML);
+    let outputs_file = outputs_file(project_dir);
+    let output = ActiveOutput {
+        env,
+        configmap_name,
+    };
+    let mut active = Vec::new();
+    let mut disabled = Vec::new();
+    let mut tracked = Vec::new();
+    for (name, target, format) in formats(&env.outputs) {
+        let file = active_dir(project_dir).join(format.file_name());
+        match target {
+            OutputTarget::Enabled(true) => active.push((
+                file,
+                format.render(&output)?,
+                format.contains_secrets(&output),
+            )),
+            OutputTarget::Path(path) => {
+                let key = path.to_string_lossy().into_owned();
+                if !is_within(&key, project_dir, true) {
+                    return Err(Error::InvalidOutput {
+                        output: name.to_string(),
+                        activate_file,
+                        message: format!(
+                            "The path `{}` is outside of the `activate.toml` directory.",
+                            key
+                        ),
+                    });
+                }
+                tracked.push(TrackedOutput {
+                    target: key,
+                    active_output: recorded_format(name),
+                    is_template: false,
+                    contents: format.render(&output)?,
+                    restricted: format.contains_secrets(&output),
+                });
+                // written by a previous activation, before its path was configured
+                disabled.push(file);
+            }
+            // written by a previous activation, before it was disabled
+            OutputTarget::Enabled(false) => disabled.push(file),
+        }
+    }
+
+    if env.env_name.is_some() {
+        for (template, target) in env.outputs.templates.iter() {
+            let key = target.to_string_lossy().into_owned();
+            for (kind, path, is_target) in [("template", template, false), ("target", target, true)]
+            {
+                if !is_within(&path.to_string_lossy(), project_dir, is_target) {
+                    return Err(Error::InvalidTemplate {
+                        target: key,
+                        activate_file,
+                        message: format!(
+                            "The {} `{}` is outside of the `activate.toml` directory.",
+                            kind,
+                            path.display()
+                        ),
+                    });
+                }
+            }
+            let template_path = project_dir.join(template);
+            let source = fs::read_to_string(&template_path)
+                .context(|| format!("Could not read `{}` file.", template_path.display()))?;
+            tracked.push(TrackedOutput {
+                target: key,
+                active_output: ActiveTemplate {
+                    template: template.to_string_lossy().into_owned(),
+                    checksum: None,
+                    allow_outside_root: false,
+                },
+                is_template: true,
+                contents: template::render(&template_path, &source, &env.new_env)?,
+                restricted: !env.secrets.is_empty(),
+            });
+        }
+    }
+    tracked.sort_by(|output1, output2| output1.target.cmp(&output2.target));
+
+    let recorded = read_outputs(&outputs_file)?;
+    for output in tracked.iter() {
+        let path = project_dir.join(&output.target);
+        if !recorded.contains_key(&output.target) && (path.exists() || path.is_symlink()) {
+            return Err(if output.is_template {
+                Error::TemplateTargetExists {
+                    target: output.target.clone(),
+                    path,
+                    activate_file,
+                }
+            } else {
+                Error::OutputExists {
+                    output: output.active_output.template.clone(),
+                    path,
+                    activate_file,
+                }
+            });
+        }
+    }
+
+    Ok(ActiveFiles {
+        active,
+        disabled,
+        tracked,
+        outputs_file,
+        recorded,
+        project_dir: project_dir.clone(),
+    })
+}
+
+impl ActiveFiles {
+    /// Writes the rendered files, recording those at a configured path, and removes those not written anymore.
+    fn write(mut self) -> Result<()> {
+        for (file, contents, restricted) in self.active.iter() {
+            write_output(file, contents, *restricted)?;
+        }
+        for file in self.disabled.iter() {
+            if file.exists() {
+                fs::remove_file(file)
+                    .context(|| format!("Could not remove `{}` file.", file.display()))?;
+            }
+        }
+
+        // written before, but not anymore
+        let mut stale = self
+            .recorded
+            .keys()
+            .filter(|target| !self.tracked.iter().any(|output| &&output.target == target))
+            .cloned()
+            .collect::<Vec<_>>();
+        stale.sort();
+        for target in stale {
+            let path = self.project_dir.join(&target);
+            if path.is_file() {
+                fs::remove_file(&path)
+                    .context(|| format!("Could not remove output `{}`.", path.display()))?;
+            }
+            self.recorded.remove(&target);
+            record_outputs(&self.outputs_file, &self.recorded)?;
+        }
+
+        for output in self.tracked {
+            let path = self.project_dir.join(&output.target);
+            write_output(&path, &output.contents, output.restricted)?;
+            if self.recorded.get(&output.target) != Some(&output.active_output) {
+                self.recorded.insert(output.target, output.active_output);
+                record_outputs(&self.outputs_file, &self.recorded)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats written to a configured path are recorded in the outputs state by the name of the format.
+fn recorded_format(name: &str) -> ActiveTemplate {
+    ActiveTemplate {
+        template: name.to_string(),
+        checksum: None,
+        allow_outside_root: false,
+    }
+}
+
+fn outputs_file(project_dir: &Path) -> PathBuf {
+    project_dir
+        .join(ACTIVATE_DIR)
+        .join(ACTIVATE_STATE_DIR)
+        .join(STATE_OUTPUTS_FILE)
+}
+
+fn read_outputs(outputs_file: &Path) -> Result<HashMap<String, ActiveTemplate>> {
+    if !outputs_file.exists() {
+        return Ok(HashMap::new());
+    }
+    Ok(read_templates(outputs_file)?.0.unwrap_or_default())
+}
+
+/// Replaces the outputs state with `recorded`, removing it if empty.
+fn record_outputs(outputs_file: &Path, recorded: &HashMap<String, ActiveTemplate>) -> Result<()> {
+    if recorded.is_empty() {
+        if outputs_file.exists() {
+            fs::remove_file(outputs_file)
+                .context(|| format!("Could not remove `{}` file.", outputs_file.display()))?;
+        }
+        return Ok(());
+    }
+    if let Some(dir) = outputs_file.parent() {
+        fs::create_dir_all(dir)
+            .context(|| format!("Could not create `{}` directory.", dir.display()))?;
+    }
+    write_templates(outputs_file, recorded)
+}
+
+fn formats(outputs: &OutputSettings) -> [(&'static str, &OutputTarget, &dyn OutputFormat); 9] {
+    [
+        ("dotenv", &outputs.dotenv, &DotEnv),
+        ("json", &outputs.json, &Json),
+        ("configmap", &outputs.configmap, &ConfigMap),
+        ("secret", &outputs.secret, &Secret),
+        ("systemd", &outputs.systemd, &Systemd),
+        ("properties", &outputs.properties, &Properties),
+        ("tfvars", &outputs.tfvars, &Tfvars),
+        ("helm", &outputs.helm, &Helm),
+        ("direnv", &outputs.direnv, &Direnv),
+    ]
+}
+
+fn active_dir(project_dir: &Path) -> PathBuf {
+    project_dir.join(ACTIVATE_DIR).join(ACTIVATE_ACTIVE_DIR)
+}
+
+/// Writes `contents` to `file`. If `restricted`, the file is only readable by the owner before anything is written.
+fn write_output(file: &Path, contents: &str, restricted: bool) -> Result<()> {
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir)
+            .context(|| format!("Could not create `{}` directory.", dir.display()))?;
+    }
+    if restricted && file.exists() {
+        restrict_permissions(file)?;
+    }
+    let mut options = File::options();
+    options.create(true).write(true).truncate(true);
+    #[cfg(unix)]
+    if restricted {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(file)
+        .and_then(|mut opened| opened.write_all(contents.as_bytes()))
+        .context(|| format!("Could not write to `{}` file.", file.display()))
+}
+
+//************************************************************************//
+
+/// The active environment of a project, to be written by each [OutputFormat].
+struct ActiveOutput<'a> {
+    env: &'a NewAndOldEnv,
+    configmap_name: Option<&'a str>,
+}
+
+impl ActiveOutput<'_> {
+    /// The env variables, sorted by key.
+    fn vars(&self) -> Vec<(&String, &String)> {
+        let mut vars = self.env.new_env.iter().collect::<Vec<_>>();
+        vars.sort();
+        vars
+    }
+
+    /// The env variables for the Kubernetes Secret and those for the ConfigMap, sorted by key.
+    #[allow(clippy::type_complexity)]
+    fn kubernetes_vars(&self) -> (Vec<(&String, &String)>, Vec<(&String, &String)>) {
+        let env = self.env;
+        self.vars()
+            .into_iter()
+            .partition(|(k, _)| env.secrets.contains(*k) || env.k8s.secret_keys.contains(*k))
+    }
+
+    fn configmap_name(&self) -> &str {
+        self.configmap_name
+            .or(self.env.k8s.configmap.name.as_deref())
+            .unwrap_or(DEFAULT_CONFIGMAP_NAME)
+    }
+}
+
+/// A format the active environment can be written in.
+trait OutputFormat {
+    /// The name of the file in the `.activate/active` directory, if no path is configured.
+    fn file_name(&self) -> &'static str;
+
+    fn render(&self, output: &ActiveOutput) -> Result<String>;
+
+    /// Whether the rendered file contains secret values.
+    fn contains_secrets(&self, output: &ActiveOutput) -> bool {
+        !output.env.secrets.is_empty()
+    }
+}
+
+struct DotEnv;
+
+/// Escapes the characters that are special in a double quoted .env value.
+fn escape_dotenv(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('$', "\\$")
+}
+
+impl OutputFormat for DotEnv {
+    fn file_name(&self) -> &'static str {
+        ALL_ENV_FILE
+    }
+
+    fn render(&self, output: &ActiveOutput) -> Result<String> {
+        let flavor = output.env.outputs.dotenv_flavor;
+        let mut rendered = GENERATED_HEADER.to_string();
+        for (k, v) in output.vars() {
+            let unsupported = match flavor {
+                DotenvFlavor::Plain => v.contains(['\n', '\r']),
+                DotenvFlavor::Dotenv => v.contains('\r'),
+                DotenvFlavor::Docker => false,
+            };
+            if unsupported {
+                return Err(Error::InvalidValue {
+                    key: k.clone(),
+                    value: output.env.display_value(k).map(|value| value.to_string()),
+                    activate_file: output.env.activate_toml_file_directory.join(ACTIVATE_TOML),
+                    message: format!(
+                        "It contains a line break, which can not be written to a `{}` .env file.",
+                        flavor.name()
+                    ),
+                });
+            }
+            let v = match flavor {
+                DotenvFlavor::Plain => v.clone(),
+                DotenvFlavor::Dotenv => format!("\"{}\"", escape_dotenv(v).replace('\n', "\\n")),
+                DotenvFlavor::Docker => format!("\"{}\"", escape_dotenv(v)),
+            };
+            rendered.push_str(&format!("{}={}\n", k, v));
+        }
+        Ok(rendered)
+    }
+}
+
+struct Json;
+
+impl OutputFormat for Json {
+    fn file_name(&self) -> &'static str {
+        ALL_ENV_JSON_FILE
+    }
+
+    fn render(&self, output: &ActiveOutput) -> Result<String> {
+        Ok(serde_json::to_string_pretty(&output.env.new_env)
+            .expect("Could not serialize environment variables to json."))
+    }
+}
+
+struct ConfigMap;
+
+impl OutputFormat for ConfigMap {
+    fn file_name(&self) -> &'static str {
+        ALL_ENV_CONFIGMAP_FILE
+    }
+
+    fn render(&self, output: &ActiveOutput) -> Result<String> {
+        let settings = &output.env.k8s.configmap;
+        let (_, configmap_vars) = output.kubernetes_vars();
+        Ok(Manifest {
+            api_version: "v1",
+            kind: "ConfigMap",
+            metadata: Metadata::new(settings, output.configmap_name()),
+            immutable: settings.immutable,
+            r#type: None,
+            data: configmap_vars
+                .into_iter()
+                .map(|(k, v)| (k.as_str(), v.clone()))
+                .collect(),
+        }
+        .to_yaml())
+    }
+
+    fn contains_secrets(&self, _output: &ActiveOutput) -> bool {
+        false
+    }
+}
+
+struct Secret;
+
+impl OutputFormat for Secret {
+    fn file_name(&self) -> &'static str {
+        ALL_ENV_SECRET_FILE
+    }
+
+    fn render(&self, output: &ActiveOutput) -> Result<String> {
+        let settings = &output.env.k8s.secret;
+        let (secret_vars, _) = output.kubernetes_vars();
+        Ok(Manifest {
+            api_version: "v1",
+            kind: "Secret",
+            metadata: Metadata::new(
+                settings,
+                settings.name.as_deref().unwrap_or(output.configmap_name()),
+            ),
+            immutable: settings.immutable,
+            r#type: Some("Opaque"),
+            data: secret_vars
+                .into_iter()
+                .map(|(k, v)| (k.as_str(), STANDARD.encode(v)))
+                .collect(),
+        }
+        .to_yaml())
+    }
+
+    fn contains_secrets(&self, output: &ActiveOutput) -> bool {
+        !output.kubernetes_vars().0.is_empty()
+    }
+}
+
+/// A systemd `EnvironmentFile`.
+struct Systemd;
+
+impl OutputFormat for Systemd {
+    fn file_name(&self) -> &'static str {
+        ALL_ENV_SYSTEMD_FILE
+    }
+
+    fn render(&self, output: &ActiveOutput) -> Result<String> {
+        Ok(output
+            .vars()
+            .iter()
+            .fold(GENERATED_HEADER.to_string(), |mut s, (k, v)| {
+                let v = v.replace('\\', "\\\\").replace('"', "\\\"");
+                s.push_str(&format!("{}=\"{}\"\n", k, v));
+                s
+            }))
+    }
+}
+
+/// A Java `.properties` file.
+struct Properties;
+
+impl OutputFormat for Properties {
+    fn file_name(&self) -> &'static str {
+        ALL_ENV_PROPERTIES_FILE
+    }
+
+    fn render(&self, output: &ActiveOutput) -> Result<String> {
+        Ok(output
+            .vars()
+            .iter()
+            .fold(GENERATED_HEADER.to_string(), |mut s, (k, v)| {
+                s.push_str(&format!(
+                    "{}={}\n",
+                    escape_properties(k, true),
+                    escape_properties(v, false)
+                ));
+                s
+            }))
+    }
+}
+
+fn escape_properties(s: &str, is_key: bool) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for (i, c) in s.chars().enumerate() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '\u{c}' => escaped.push_str("\\f"),
+            '=' | ':' | '#' | '!' if is_key || i == 0 => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            ' ' if is_key || i == 0 => escaped.push_str("\\ "),
+            c if !c.is_ascii() || c.is_ascii_control() => {
+                let mut units = [0; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    escaped.push_str(&format!("\\u{:04x}", unit));
+                }
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A Terraform `.tfvars` file.
+struct Tfvars;
+
+impl OutputFormat for Tfvars {
+    fn file_name(&self) -> &'static str {
+        ALL_ENV_TFVARS_FILE
+    }
+
+    fn render(&self, output: &ActiveOutput) -> Result<String> {
+        Ok(output
+            .vars()
+            .iter()
+            .fold(GENERATED_HEADER.to_string(), |mut s, (k, v)| {
+                let v = serde_json::to_string(v)
+                    .expect("Could not serialize environment variable to json.")
+                    .replace("${", "$${")
+                    .replace("%{", "%%{");
+                s.push_str(&format!("{} = {}\n", k, v));
+                s
+            }))
+    }
+}
+
+/// A Helm `values.yaml` file, with the env variables under `env`.
+struct Helm;
+
+impl OutputFormat for Helm {
+    fn file_name(&self) -> &'static str {
+        ALL_ENV_HELM_FILE
+    }
+
+    fn render(&self, output: &ActiveOutput) -> Result<String> {
+        let env = output.vars().into_iter().collect::<BTreeMap<_, _>>();
+        let values = BTreeMap::from([("env", env)]);
+        Ok(format!(
+            "{GENERATED_HEADER}{}",
+            serde_yaml::to_string(&values)
+                .expect("Could not serialize environment variables to yaml.")
+        ))
+    }
+}
+
+/// A direnv `.envrc` file.
+struct Direnv;
+
+impl OutputFormat for Direnv {
+    fn file_name(&self) -> &'static str {
+        ALL_ENV_DIRENV_FILE
+    }
+
+    fn render(&self, output: &ActiveOutput) -> Result<String> {
+        Ok(output
+            .vars()
+            .iter()
+            .fold(GENERATED_HEADER.to_string(), |mut s, (k, v)| {
+                s.push_str(&format!("export {}='{}'\n", k, v.replace('\'', "'\\''")));
+                s
+            }))
+    }
+}
+
+//************************************************************************//
+
+/// A generated Kubernetes manifest.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest<'a> {
+    api_version: &'static str,
+    kind: &'static str,
+    metadata: Metadata<'a>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    immutable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    r#type: Option<&'static str>,
+    data: BTreeMap<&'a str, String>,
+}
+
+#[derive(Serialize)]
+struct Metadata<'a> {
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespace: Option<&'a str>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    labels: &'a BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    annotations: &'a BTreeMap<String, String>,
+}
+
+impl<'a> Metadata<'a> {
+    fn new(settings: &'a ManifestSettings, name: &'a str) -> Metadata<'a> {
+        Metadata {
+            name,
+            namespace: settings.namespace.as_deref(),
+            labels: &settings.labels,
+            annotations: &settings.annotations,
+        }
+    }
+}
+
+impl Manifest<'_> {
+    fn to_yaml(&self) -> String {
+        format!(
+            "{GENERATED_HEADER}{}",
+            serde_yaml::to_string(self).expect("Could not serialize manifest to yaml.")
+        )
+    }
+}
@@ -0,0 +1,596 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    error::{Context, Error, Result},
+    merge::NewAndOldEnv,
+    output::{ensure_active_files_exist, KubernetesSettings, OutputSettings},
+    state::{
+        activate_new, create_gitignore_file, create_readmes, decativate_current, expand_links,
+        read_activation, read_env, read_links, read_templates, Deactivated,
+    },
+    ActivateOptions, ACTIVATE_DIR, ACTIVATE_STATE_DIR, ACTIVATE_TOML, STATE_ACTIVATION_FILE,
+    STATE_ENV_FILE, STATE_LINKS_FILE, STATE_OUTPUTS_FILE, STATE_TEMPLATES_FILE,
+};
+
+/// A directory with an `activate.toml` file.
+#[derive(Debug)]
+pub struct Project {
+    dir: PathBuf,
+    toml: ActivateToml,
+}
+
+/// An environment of a [Project], with any inherited env variables resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Environment {
+    pub name: String,
+    pub env: HashMap<String, String>,
+    /// Target paths, mapped to the link to create there.
+    pub links: HashMap<String, Link>,
+    /// Target paths, mapped to the template rendered to them.
+    pub templates: HashMap<String, Template>,
+    /// Keys of `env` that were inherited from an ancestor `activate.toml` file.
+    pub inherited: HashSet<String>,
+    /// Keys of `env` whose values are secret.
+    pub secrets: HashSet<String>,
+    /// Keys of `env` whose values are defaults, only set if the caller has not set them already.
+    pub defaults: HashSet<String>,
+    pub k8s: KubernetesSettings,
+    /// If set, the environment is protected, and its activation must be confirmed by typing this phrase.
+    pub confirmation_phrase: Option<String>,
+    /// If set, the environment is deactivated once it has been active this long.
+    pub ttl: Option<Duration>,
+}
+
+/// A file or directory of an [Environment], made available at a target path.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Link {
+    /// Relative to the directory of the `activate.toml` file.
+    pub source: String,
+    #[serde(default)]
+    pub mode: LinkMode,
+    /// If true, each file in the source directory is linked into the target directory on its own, leaving the other
+    /// files of the target directory alone.
+    #[serde(default)]
+    pub mirror: bool,
+    /// If true, a missing source is skipped with a warning instead of failing the activation.
+    #[serde(default)]
+    pub optional: bool,
+    /// The link is only created if the condition holds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub when: Option<LinkCondition>,
+    /// If true, the target and source may be outside of the directory of the `activate.toml` file.
+    #[serde(default)]
+    pub allow_outside_root: bool,
+}
+
+/// A template of an [Environment], rendered with its env variables to a target path.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Template {
+    /// Relative to the directory of the `activate.toml` file.
+    pub template: String,
+    /// If true, the target and template may be outside of the directory of the `activate.toml` file.
+    #[serde(default)]
+    pub allow_outside_root: bool,
+}
+
+/// When a [Link] is created. Every set condition must hold.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LinkCondition {
+    /// A path, relative to the directory of the `activate.toml` file, that must exist.
+    pub exists: Option<String>,
+    /// An env variable that must be set to a non-empty value.
+    pub env: Option<String>,
+}
+
+impl LinkCondition {
+    /// Whether the condition holds for the project in `current_dir`.
+    pub fn holds(&self, current_dir: &Path) -> bool {
+        self.exists
+            .as_ref()
+            .is_none_or(|path| current_dir.join(path).exists())
+            && self
+                .env
+                .as_ref()
+                .is_none_or(|key| std::env::var_os(key).is_some_and(|value| !value.is_empty()))
+    }
+}
+
+/// How a [Link] is created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkMode {
+    #[default]
+    Symlink,
+    /// The source is copied. Local modifications are kept on deactivation.
+    Copy,
+    /// Files only.
+    Hardlink,
+}
+
+/// What is currently activated in a [Project].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProjectState {
+    /// The name of the active environment.
+    pub env_name: Option<String>,
+    /// When the active environment expires, if it has a `ttl`.
+    pub expires_at: Option<SystemTime>,
+    pub env: HashMap<String, String>,
+    /// Target paths, mapped to the source of the link.
+    pub links: HashMap<String, String>,
+    pub templates: HashMap<String, String>,
+}
+
+impl Environment {
+    /// Without the defaults the caller has set already. A caller's value equal to the value in `activated_env`, the env
+    /// of the previous activation, was set by that activation, so the default is still set.
+    fn without_caller_defaults(&self, activated_env: &HashMap<String, String>) -> Environment {
+        let mut environment = self.clone();
+        environment.env.retain(|key, _| {
+            !self.defaults.contains(key)
+                || std::env::var(key)
+                    .ok()
+                    .is_none_or(|value| activated_env.get(key) == Some(&value))
+        });
+        environment
+    }
+}
+
+impl ProjectState {
+    /// Whether the active environment has expired, as its `ttl` passed.
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= SystemTime::now())
+    }
+}
+
+impl Project {
+    /// Loads the `activate.toml` file in `dir`.
+    pub fn load(dir: impl Into<PathBuf>) -> Result<Project> {
+        let dir = dir.into();
+        let toml = read_activate_toml(&dir.join(ACTIVATE_TOML))?;
+        Ok(Project { dir, toml })
+    }
+
+    /// The directory containing the `activate.toml` file.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The names of the environments defined, sorted.
+    pub fn environment_names(&self) -> Vec<&str> {
+        let mut names = self
+            .toml
+            .environments
+            .keys()
+            .map(|name| name.as_str())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// Resolves the environment `name`, if defined.
+    pub fn environment(&self, name: &str) -> Result<Option<Environment>> {
+        let Some(EnvironmentData {
+            env,
+            links,
+            templates,
+            k8s,
+            protected,
+            ttl,
+        }) = self.toml.environments.get(name)
+        else {
+            return Ok(None);
+        };
+        let mut env = env.clone().unwrap_or_default();
+        let mut inherited = HashSet::new();
+        if self.toml.inherit {
+            let mut inherited_env = inherited_env(&self.dir, name)?;
+            inherited = inherited_env
+                .keys()
+                .filter(|key| !env.contains_key(*key))
+                .cloned()
+                .collect();
+            inherited_env.extend(env);
+            env = inherited_env;
+        }
+        let secrets = env
+            .iter()
+            .filter(|(_, value)| value.is_secret())
+            .map(|(key, _)| key.clone())
+            .collect();
+        let defaults = env
+            .iter()
+            .filter(|(_, value)| value.is_default())
+            .map(|(key, _)| key.clone())
+            .collect();
+        Ok(Some(Environment {
+            name: name.to_string(),
+            env: env
+                .into_iter()
+                .map(|(key, value)| (key, value.into_value()))
+                .collect(),
+            links: links
+                .iter()
+                .flatten()
+                .map(|(target, link)| (target.clone(), link.clone().into()))
+                .collect(),
+            templates: templates
+                .iter()
+                .flatten()
+                .map(|(target, template)| (target.clone(), template.clone().into()))
+                .collect(),
+            inherited,
+            secrets,
+            defaults,
+            k8s: k8s.clone(),
+            confirmation_phrase: match protected {
+                Some(Protection::Enabled(true)) => Some(name.to_string()),
+                Some(Protection::Phrase(phrase)) => Some(phrase.clone()),
+                Some(Protection::Enabled(false)) | None => None,
+            },
+            ttl: *ttl,
+        }))
+    }
+
+    /// Reads what is currently activated.
+    pub fn state(&self) -> Result<ProjectState> {
+        let state_dir = self.dir.join(ACTIVATE_DIR).join(ACTIVATE_STATE_DIR);
+        let env_file = state_dir.join(STATE_ENV_FILE);
+        let links_file = state_dir.join(STATE_LINKS_FILE);
+        let templates_file = state_dir.join(STATE_TEMPLATES_FILE);
+        let activation_file = state_dir.join(STATE_ACTIVATION_FILE);
+        let mut state = ProjectState::default();
+        if activation_file.exists() {
+            let activation = read_activation(&activation_file)?;
+            state.expires_at = activation.expiry(&activation_file)?;
+            state.env_name = Some(activation.env_name);
+        }
+        if env_file.exists() {
+            state.env = read_env(&env_file)?.0.unwrap_or_default();
+        }
+        if links_file.exists() {
+            state.links = read_links(&links_file)?
+                .0
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(target, link)| (target, link.source))
+                .collect();
+        }
+        if templates_file.exists() {
+            state.templates = read_templates(&templates_file)?
+                .0
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(target, template)| (target, template.template))
+                .collect();
+        }
+        Ok(state)
+    }
+
+    /// The paths of the links, the directories created for them, and the rendered templates and outputs recorded in the
+    /// state, relative to the project.
+    pub(crate) fn recorded_paths(&self) -> Result<Vec<String>> {
+        let state_dir = self.dir.join(ACTIVATE_DIR).join(ACTIVATE_STATE_DIR);
+        let links_file = state_dir.join(STATE_LINKS_FILE);
+        let mut paths = Vec::new();
+        if links_file.exists() {
+            for (target, link) in read_links(&links_file)?.0.unwrap_or_default() {
+                paths.push(target);
+                paths.extend(link.dirs);
+            }
+        }
+        for file in [STATE_TEMPLATES_FILE, STATE_OUTPUTS_FILE] {
+            let file = state_dir.join(file);
+            if file.exists() {
+                paths.extend(read_templates(&file)?.0.unwrap_or_default().into_keys());
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Deactivates the active environment, if any, and activates `environment`, if provided. Changed links are only
+    /// removed if `options.force` is set.
+    pub(crate) fn apply(
+        &self,
+        environment: Option<&Environment>,
+        options: &ActivateOptions,
+    ) -> Result<NewAndOldEnv> {
+        let current_dir = self.dir.as_path();
+        let activate_dir = current_dir.join(ACTIVATE_DIR);
+        let state_dir = activate_dir.join(ACTIVATE_STATE_DIR);
+
+        ensure_active_files_exist(current_dir, &self.toml.outputs)?;
+
+        let links = match environment {
+            Some(environment) => expand_links(&environment.links, current_dir)?,
+            None => Vec::new(),
+        };
+        let next_targets = links
+            .iter()
+            .map(|(target, _)| target.as_str())
+            .collect::<HashSet<_>>();
+        let deactivated = if state_dir.exists() {
+            decativate_current(&state_dir, current_dir, &next_targets, options.force)?
+        } else {
+            if environment.is_some() {
+                fs::create_dir_all(&state_dir).context(|| {
+                    format!(
+                        "Could not create `{}` directory.",
+                        state_dir.to_string_lossy()
+                    )
+                })?;
+                create_gitignore_file(&activate_dir)?;
+                create_readmes(&activate_dir)?;
+            }
+            Deactivated::default()
+        };
+
+        let old_env = deactivated.env.and_then(|e| e.0).unwrap_or_default();
+        let environment =
+            environment.map(|environment| environment.without_caller_defaults(&old_env));
+        let environment = environment.as_ref();
+        if let Some(environment) = environment {
+            activate_new(
+                environment,
+                options.collision,
+                &links,
+                &deactivated.replaced_links,
+                &state_dir,
+                current_dir,
+            )?;
+        }
+
+        let new_env = environment
+            .map(|environment| environment.env.clone())
+            .unwrap_or_default();
+        let mut env = self.new_and_old_env(environment, old_env, new_env);
+        env.old_collision = deactivated.collision;
+        Ok(env)
+    }
+
+    /// The env [Project::apply] would activate, without changing anything.
+    pub(crate) fn planned(&self, environment: Option<&Environment>) -> Result<NewAndOldEnv> {
+        let state = self.state()?;
+        let environment =
+            environment.map(|environment| environment.without_caller_defaults(&state.env));
+        let new_env = environment
+            .as_ref()
+            .map(|environment| environment.env.clone())
+            .unwrap_or_default();
+        Ok(self.new_and_old_env(environment.as_ref(), HashMap::new(), new_env))
+    }
+
+    /// The env of the active environment recorded in `state`, left as it is.
+    pub(crate) fn current(&self, state: &ProjectState) -> Result<NewAndOldEnv> {
+        let environment = match &state.env_name {
+            Some(env_name) => self.environment(env_name)?,
+            None => None,
+        };
+        let mut env = self.new_and_old_env(environment.as_ref(), HashMap::new(), state.env.clone());
+        env.env_name = state.env_name.clone();
+        Ok(env)
+    }
+
+    fn new_and_old_env(
+        &self,
+        environment: Option<&Environment>,
+        old_env: HashMap<String, String>,
+        new_env: HashMap<String, String>,
+    ) -> NewAndOldEnv {
+        NewAndOldEnv {
+            activate_toml_file_directory: self.dir.clone(),
+            prefix: self.toml.prefix.clone(),
+            inherited: environment
+                .map(|environment| environment.inherited.clone())
+                .unwrap_or_default(),
+            secrets: environment
+                .map(|environment| environment.secrets.clone())
+                .unwrap_or_default(),
+            k8s: environment
+                .map(|environment| environment.k8s.clone())
+                .unwrap_or_default(),
+            outputs: self.toml.outputs.clone(),
+            env_name: environment.map(|environment| environment.name.clone()),
+            old_collision: None,
+            old_env,
+            new_env,
+        }
+    }
+
+    /// Resolves the environment `name`, failing if it is not defined.
+    pub(crate) fn expect_environment(&self, name: &str) -> Result<Environment> {
+        let activate_file = self.dir.join(ACTIVATE_TOML);
+        if self.toml.environments.is_empty() {
+            return Err(Error::NoEnvironments(activate_file));
+        }
+        self.environment(name)?
+            .ok_or_else(|| Error::UnknownEnvironment {
+                env_name: name.to_string(),
+                activate_file,
+            })
+    }
+}
+
+fn read_activate_toml(activate_file: &Path) -> Result<ActivateToml> {
+    let contents = fs::read_to_string(activate_file)
+        .context(|| format!("Could not read `{}` file.", activate_file.display()))?;
+    toml::from_str(&contents)
+        .map_err(|err| Error::parse(activate_file, &contents, err.span(), err.message()))
+}
+
+/// The env variables of `env_name` that a project in `dir` inherits from the nearest ancestor `activate.toml` file.
+/// Cascades further up the tree if the ancestor inherits as well.
+fn inherited_env(dir: &Path, env_name: &str) -> Result<HashMap<String, EnvValue>> {
+    let dir = dir
+        .canonicalize()
+        .context(|| format!("Could not resolve `{}`.", dir.display()))?;
+    let Some(ancestor_file) = dir
+        .ancestors()
+        .skip(1)
+        .map(|ancestor| ancestor.join(ACTIVATE_TOML))
+        .find(|ancestor_file| ancestor_file.exists())
+    else {
+        return Ok(HashMap::new());
+    };
+    let mut ancestor = read_activate_toml(&ancestor_file)?;
+    let mut env = if ancestor.inherit {
+        inherited_env(ancestor_file.parent().unwrap(), env_name)?
+    } else {
+        HashMap::new()
+    };
+    if let Some(ancestor_env) = ancestor
+        .environments
+        .remove(env_name)
+        .and_then(|data| data.env)
+    {
+        env.extend(ancestor_env);
+    }
+    Ok(env)
+}
+
+#[derive(Debug, Deserialize)]
+struct ActivateToml {
+    /// Prefix for this project's variables when merged into an ancestor with the `prefix` collision strategy.
+    prefix: Option<String>,
+    /// If true, each environment also takes the env variables of the same environment in the nearest ancestor
+    /// `activate.toml` file. Variables defined here take precedence.
+    #[serde(default)]
+    inherit: bool,
+    /// The formats the active environment is written in.
+    #[serde(default)]
+    outputs: OutputSettings,
+    #[serde(flatten)]
+    environments: HashMap<String, EnvironmentData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EnvironmentData {
+    env: Option<HashMap<String, EnvValue>>,
+    links: Option<HashMap<String, LinkValue>>,
+    templates: Option<HashMap<String, TemplateValue>>,
+    #[serde(default)]
+    k8s: KubernetesSettings,
+    protected: Option<Protection>,
+    #[serde(default, deserialize_with = "deserialize_ttl")]
+    ttl: Option<Duration>,
+}
+
+/// A duration such as `30m` or `1h 30m`.
+fn deserialize_ttl<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<Duration>, D::Error> {
+    let ttl = String::deserialize(deserializer)?;
+    humantime::parse_duration(&ttl)
+        .map(Some)
+        .map_err(|err| serde::de::Error::custom(format!("Invalid ttl `{}`. {}", ttl, err)))
+}
+
+/// Whether activating an environment must be confirmed, either `protected = true` to type the environment name, or
+/// `protected = "<PHRASE>"` to type the phrase.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum Protection {
+    Enabled(bool),
+    Phrase(String),
+}
+
+/// A link, either `"target" = "source"` or `"target" = { source = "source", mode = "copy" }`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum LinkValue {
+    Source(String),
+    Detailed(Link),
+}
+
+impl From<LinkValue> for Link {
+    fn from(value: LinkValue) -> Self {
+        match value {
+            LinkValue::Source(source) => Link {
+                source,
+                mode: LinkMode::Symlink,
+                mirror: false,
+                optional: false,
+                when: None,
+                allow_outside_root: false,
+            },
+            LinkValue::Detailed(link) => link,
+        }
+    }
+}
+
+/// A template, either `"target" = "template"` or `"target" = { template = "template", allow_outside_root = true }`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum TemplateValue {
+    Template(String),
+    Detailed(Template),
+}
+
+impl From<TemplateValue> for Template {
+    fn from(value: TemplateValue) -> Self {
+        match value {
+            TemplateValue::Template(template) => Template {
+                template,
+                allow_outside_root: false,
+            },
+            TemplateValue::Detailed(template) => template,
+        }
+    }
+}
+
+/// The value of an env variable, either `KEY = "value"` or `KEY = { value = "value", secret = true }`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum EnvValue {
+    Plain(String),
+    Detailed(EnvValueData),
+    Default(EnvDefaultData),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EnvValueData {
+    value: String,
+    /// If true, the value is masked when displayed, the active files containing it are only readable by the owner,
+    /// and it is left out of the `configmap`.
+    #[serde(default)]
+    secret: bool,
+}
+
+/// `KEY = { default = "value" }`, only set if the caller has not set `KEY` already.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EnvDefaultData {
+    default: String,
+    #[serde(default)]
+    secret: bool,
+}
+
+impl EnvValue {
+    fn is_secret(&self) -> bool {
+        match self {
+            EnvValue::Plain(_) => false,
+            EnvValue::Detailed(data) => data.secret,
+            EnvValue::Default(data) => data.secret,
+        }
+    }
+
+    fn is_default(&self) -> bool {
+        matches!(self, EnvValue::Default(_))
+    }
+
+    fn into_value(self) -> String {
+        match self {
+            EnvValue::Plain(value) => value,
+            EnvValue::Detailed(data) => data.value,
+            EnvValue::Default(data) => data.default,
+        }
+    }
+}
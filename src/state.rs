@@ -0,0 +1,1331 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::Write,
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{
+    error::{line_column, Context, Error, Result},
+    merge::CollisionStrategy,
+    notice,
+    project::{Environment, Link, LinkMode, Template},
+    template, warn, ACTIVATE_ACTIVE_DIR, ACTIVATE_STATE_DIR, ACTIVATE_TOML, STATE_ACTIVATION_FILE,
+    STATE_ENV_FILE, STATE_LINKS_FILE, STATE_TEMPLATES_FILE,
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ActiveEnvironmentEnv(pub(crate) Option<HashMap<String, String>>);
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ActiveEnvironmentLinks(pub(crate) Option<HashMap<String, ActiveLink>>);
+
+/// A link recorded in the links state.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "ActiveLinkEntry")]
+pub(crate) struct ActiveLink {
+    pub(crate) source: String,
+    pub(crate) mode: LinkMode,
+    /// Of the copied source, for copies.
+    pub(crate) checksum: Option<String>,
+    /// The parent directories of the link that were created for it, relative to the `activate.toml` directory.
+    pub(crate) dirs: Vec<String>,
+    pub(crate) allow_outside_root: bool,
+}
+
+/// Symlinks without created directories are recorded as just their source.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ActiveLinkEntry {
+    Source(String),
+    Detailed {
+        source: String,
+        #[serde(default)]
+        mode: LinkMode,
+        checksum: Option<String>,
+        #[serde(default)]
+        dirs: Vec<String>,
+        #[serde(default)]
+        allow_outside_root: bool,
+    },
+}
+
+impl ActiveLink {
+    /// The line recording the link at `key` in the links state.
+    pub(crate) fn to_entry(&self, key: &str) -> String {
+        let entry = match self.mode {
+            LinkMode::Symlink if self.dirs.is_empty() && !self.allow_outside_root => {
+                format!("\"{}\"", self.source)
+            }
+            _ => {
+                let mut entry = toml::Table::new();
+                entry.insert("source".to_string(), self.source.clone().into());
+                entry.insert(
+                    "mode".to_string(),
+                    toml::Value::try_from(self.mode).expect("Could not serialize link mode."),
+                );
+                if let Some(checksum) = &self.checksum {
+                    entry.insert("checksum".to_string(), checksum.clone().into());
+                }
+                if !self.dirs.is_empty() {
+                    entry.insert("dirs".to_string(), self.dirs.clone().into());
+                }
+                if self.allow_outside_root {
+                    entry.insert("allow_outside_root".to_string(), true.into());
+                }
+                toml::Value::Table(entry).to_string()
+            }
+        };
+        format!("\"{}\"={}\n", key, entry)
+    }
+}
+
+impl From<ActiveLinkEntry> for ActiveLink {
+    fn from(entry: ActiveLinkEntry) -> Self {
+        match entry {
+            ActiveLinkEntry::Source(source) => ActiveLink {
+                source,
+                mode: LinkMode::Symlink,
+                checksum: None,
+                dirs: Vec::new(),
+                allow_outside_root: false,
+            },
+            ActiveLinkEntry::Detailed {
+                source,
+                mode,
+                checksum,
+                dirs,
+                allow_outside_root,
+            } => ActiveLink {
+                source,
+                mode,
+                checksum,
+                dirs,
+                allow_outside_root,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ActiveEnvironmentTemplates(pub(crate) Option<HashMap<String, ActiveTemplate>>);
+
+/// A rendered template recorded in the templates state.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "ActiveTemplateEntry")]
+pub(crate) struct ActiveTemplate {
+    pub(crate) template: String,
+    /// Of the rendered file, if it is kept when modified.
+    pub(crate) checksum: Option<String>,
+    pub(crate) allow_outside_root: bool,
+}
+
+/// Templates without a checksum are recorded as just their template.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ActiveTemplateEntry {
+    Template(String),
+    Detailed {
+        template: String,
+        checksum: Option<String>,
+        #[serde(default)]
+        allow_outside_root: bool,
+    },
+}
+
+impl ActiveTemplate {
+    /// The line recording the template rendered to `key` in the templates state.
+    pub(crate) fn to_entry(&self, key: &str) -> String {
+        let entry = if self.checksum.is_none() && !self.allow_outside_root {
+            format!("\"{}\"", self.template)
+        } else {
+            let mut entry = toml::Table::new();
+            entry.insert("template".to_string(), self.template.clone().into());
+            if let Some(checksum) = &self.checksum {
+                entry.insert("checksum".to_string(), checksum.clone().into());
+            }
+            if self.allow_outside_root {
+                entry.insert("allow_outside_root".to_string(), true.into());
+            }
+            toml::Value::Table(entry).to_string()
+        };
+        format!("\"{}\"={}\n", key, entry)
+    }
+}
+
+impl From<ActiveTemplateEntry> for ActiveTemplate {
+    fn from(entry: ActiveTemplateEntry) -> Self {
+        match entry {
+            ActiveTemplateEntry::Template(template) => ActiveTemplate {
+                template,
+                checksum: None,
+                allow_outside_root: false,
+            },
+            ActiveTemplateEntry::Detailed {
+                template,
+                checksum,
+                allow_outside_root,
+            } => ActiveTemplate {
+                template,
+                checksum,
+                allow_outside_root,
+            },
+        }
+    }
+}
+
+/// What is left of the environment deactivated by [decativate_current].
+#[derive(Debug, Default)]
+pub(crate) struct Deactivated {
+    pub(crate) env: Option<ActiveEnvironmentEnv>,
+    /// The collision strategy the deactivated environment was activated with, if it was recorded.
+    pub(crate) collision: Option<CollisionStrategy>,
+    /// Links that were kept, as the next environment replaces them, mapped to the directories created for them.
+    pub(crate) replaced_links: HashMap<String, Vec<String>>,
+}
+
+/// The active environment, recorded in the activation state.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ActiveActivation {
+    pub(crate) env_name: String,
+    /// RFC 3339 timestamp, if the environment has a `ttl`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) expires_at: Option<String>,
+    /// The collision strategy the env was merged with, to unset the same keys on deactivation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) collision: Option<CollisionStrategy>,
+}
+
+impl ActiveActivation {
+    /// When the activation expires, if it does.
+    pub(crate) fn expiry(&self, current_activation_file: &Path) -> Result<Option<SystemTime>> {
+        self.expires_at
+            .as_deref()
+            .map(|expires_at| {
+                humantime::parse_rfc3339(expires_at).map_err(|err| Error::StateCorruption {
+                    file: current_activation_file.to_path_buf(),
+                    message: format!("Invalid expiry `{}`. {}", expires_at, err),
+                })
+            })
+            .transpose()
+    }
+}
+
+/// Deactivates the current environment, recorded in `state_dir`. The links at `next_targets` are kept, for the next
+/// environment to replace them atomically.
+pub(crate) fn decativate_current(
+    state_dir: &Path,
+    current_dir: &Path,
+    next_targets: &HashSet<&str>,
+    force: bool,
+) -> Result<Deactivated> {
+    let current_env_file = &state_dir.join(STATE_ENV_FILE);
+    let current_links_file = &state_dir.join(STATE_LINKS_FILE);
+    let current_templates_file = &state_dir.join(STATE_TEMPLATES_FILE);
+    let current_activation_file = &state_dir.join(STATE_ACTIVATION_FILE);
+    let mut deactivated = Deactivated::default();
+    if current_activation_file.exists() {
+        let activation = read_activation(current_activation_file)?;
+        if let Some(expiry) = activation.expiry(current_activation_file)? {
+            if expiry <= SystemTime::now() {
+                notice(format!(
+                    "The environment `{}` in `{}` expired at {} and was deactivated.",
+                    activation.env_name,
+                    current_dir.join(ACTIVATE_TOML).display(),
+                    humantime::format_rfc3339_seconds(expiry)
+                ));
+            }
+        }
+        deactivated.collision = activation.collision;
+        fs::remove_file(current_activation_file).context(|| {
+            format!(
+                "Could not remove `{}` file.",
+                current_activation_file.display()
+            )
+        })?;
+    }
+    if current_env_file.exists() {
+        deactivated.env = Some(remove_env(current_env_file)?);
+    }
+    if current_links_file.exists() {
+        deactivated.replaced_links =
+            remove_links(current_links_file, current_dir, next_targets, force)?;
+    }
+    if current_templates_file.exists() {
+        remove_templates(current_templates_file, current_dir, force)?;
+    }
+
+    Ok(deactivated)
+}
+
+/// Activates `environment`, with its links expanded by [expand_links], recording what was activated in `state_dir`.
+pub(crate) fn activate_new(
+    environment: &Environment,
+    collision: CollisionStrategy,
+    links: &[(String, Link)],
+    replaced_links: &HashMap<String, Vec<String>>,
+    state_dir: &Path,
+    current_dir: &Path,
+) -> Result<()> {
+    let has_secrets = !environment.secrets.is_empty();
+    // links and templates first, so the environment is not recorded as active if they fail
+    if !links.is_empty() {
+        add_links(
+            links,
+            replaced_links,
+            &state_dir.join(STATE_LINKS_FILE),
+            current_dir,
+        )?;
+    }
+    if !environment.templates.is_empty() {
+        add_templates(
+            &environment.templates,
+            &environment.env,
+            has_secrets,
+            &state_dir.join(STATE_TEMPLATES_FILE),
+            current_dir,
+        )?;
+    }
+    add_activation(
+        environment,
+        collision,
+        &state_dir.join(STATE_ACTIVATION_FILE),
+    )?;
+    if !environment.env.is_empty() {
+        add_env(
+            &environment.env,
+            &state_dir.join(STATE_ENV_FILE),
+            has_secrets,
+        )?;
+    }
+    Ok(())
+}
+
+//************************************************************************//
+
+/// Records the activation of `environment`, when it expires and the `collision` strategy it is merged with.
+pub(crate) fn add_activation(
+    environment: &Environment,
+    collision: CollisionStrategy,
+    activation_file: &Path,
+) -> Result<()> {
+    let activation = ActiveActivation {
+        env_name: environment.name.clone(),
+        expires_at: environment
+            .ttl
+            .map(|ttl| humantime::format_rfc3339_seconds(SystemTime::now() + ttl).to_string()),
+        collision: Some(collision),
+    };
+    fs::write(
+        activation_file,
+        serde_json::to_string(&activation).expect("Could not serialize activation"),
+    )
+    .context(|| format!("Could not write to `{}` file.", activation_file.display()))
+}
+
+pub(crate) fn read_activation(current_activation_file: &Path) -> Result<ActiveActivation> {
+    let activation_string = fs::read_to_string(current_activation_file).context(|| {
+        format!(
+            "Could not read `{}` file.",
+            current_activation_file.display()
+        )
+    })?;
+    serde_json::from_str(&activation_string).map_err(|err| Error::StateCorruption {
+        file: current_activation_file.to_path_buf(),
+        message: format!(
+            "Could not parse `{}` at line {}, column {}. {}",
+            STATE_ACTIVATION_FILE,
+            err.line(),
+            err.column(),
+            err
+        ),
+    })
+}
+
+pub(crate) fn add_env(
+    env_vars: &HashMap<String, String>,
+    env_file: &Path,
+    has_secrets: bool,
+) -> Result<()> {
+    if has_secrets && env_file.exists() {
+        restrict_permissions(env_file)?;
+    }
+    let mut options = File::options();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    if has_secrets {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(env_file)
+        .context(|| format!("Could not create `{}` file.", env_file.display()))?;
+    file.write_all(
+        serde_json::to_string(&ActiveEnvironmentEnv(Some(env_vars.clone())))
+            .expect("Could not serialize environment variables")
+            .as_bytes(),
+    )
+    .context(|| format!("Could not write to `{}` file.", env_file.display()))
+}
+
+/// Makes `file` only readable and writable by its owner, as it contains secret values.
+pub(crate) fn restrict_permissions(file: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(file, fs::Permissions::from_mode(0o600)).context(|| {
+            format!(
+                "Could not restrict permissions of `{}` file.",
+                file.display()
+            )
+        })?;
+    }
+    #[cfg(not(unix))]
+    let _ = file;
+    Ok(())
+}
+
+pub(crate) fn remove_env(current_env_file: &Path) -> Result<ActiveEnvironmentEnv> {
+    let old_env_vars = read_env(current_env_file)?;
+
+    fs::remove_file(current_env_file).context(|| {
+        format!(
+            "Could not remove `{}` file. Environemnt is still active.",
+            current_env_file.display()
+        )
+    })?;
+
+    Ok(old_env_vars)
+}
+
+pub(crate) fn read_env(current_env_file: &Path) -> Result<ActiveEnvironmentEnv> {
+    let env_string = fs::read_to_string(current_env_file)
+        .context(|| format!("Could not read `{}` file.", current_env_file.display()))?;
+    let old_env_vars_result = serde_json::from_str::<ActiveEnvironmentEnv>(&env_string);
+    match old_env_vars_result {
+        Ok(ok) => Ok(ok),
+        Err(err) => {
+            if err.is_eof() {
+                Ok(ActiveEnvironmentEnv(None))
+            } else {
+                Err(Error::StateCorruption {
+                    file: current_env_file.to_path_buf(),
+                    message: format!(
+                        "Could not parse `{}` at line {}, column {}. {}",
+                        STATE_ENV_FILE,
+                        err.line(),
+                        err.column(),
+                        err
+                    ),
+                })
+            }
+        }
+    }
+}
+
+//************************************************************************//
+
+pub(crate) fn create_gitignore_file(activate_dir: &Path) -> Result<()> {
+    fs::write(
+        activate_dir.join(".gitignore"),
+        format!(
+            "{}/\n{}/\n{}",
+            ACTIVATE_STATE_DIR, ACTIVATE_ACTIVE_DIR, "README.md"
+        ),
+    )
+    .context(|| {
+        format!(
+            "Could not create `{}` file.",
+            activate_dir.join(".gitignore").display()
+        )
+    })
+}
+
+pub(crate) fn create_readmes(activate_dir: &Path) -> Result<()> {
+    let readme = activate_dir.join("README.md");
+    fs::write(
+        &readme,
+        format!(
+            r#"This directory stores data for the currently active environment.
+Files can freely be added to this directly, but do not change the `{}` directory.
+The `{}` directory can be modified, but note changes may be overwritten."#,
+            ACTIVATE_STATE_DIR, ACTIVATE_ACTIVE_DIR
+        ),
+    )
+    .context(|| format!("Could not create `{}` file.", readme.display()))?;
+    let readme = activate_dir.join(ACTIVATE_STATE_DIR).join("README.md");
+    fs::write(
+        &readme,
+        format!(
+            r#"This directory should not be modified. It stores the links and env variables
+activated in the current environment that are from this `{}` file"#, //todo check to make sure
+            ACTIVATE_TOML
+        ),
+    )
+    .context(|| format!("Could not create `{}` file.", readme.display()))?;
+    let readme = activate_dir.join(ACTIVATE_ACTIVE_DIR).join("README.md");
+    fs::write(
+        &readme,
+        format!(
+            r#"This directory contains the activated cofig, such env variables, that
+are activated in the current environment and are from this `{}` file or any descendants if 
+activated with the `-d` flags. These files are safe to consumed"#,
+            ACTIVATE_TOML
+        ),
+    )
+    .context(|| format!("Could not create `{}` file.", readme.display()))
+}
+
+//************************************************************************//
+
+/// Expands glob and `mirror` links into a link for each file, sorted by target.
+pub(crate) fn expand_links(
+    links: &HashMap<String, Link>,
+    current_dir: &Path,
+) -> Result<Vec<(String, Link)>> {
+    let activate_file = current_dir.join(ACTIVATE_TOML);
+    let mut expanded = Vec::with_capacity(links.len());
+    for (key, link) in links {
+        if !link
+            .when
+            .as_ref()
+            .is_none_or(|when| when.holds(current_dir))
+        {
+            continue;
+        }
+        if link.optional && !is_pattern(&link.source) && !current_dir.join(&link.source).exists() {
+            warn(format!(
+                "The source `{}` of the optional link `{}` does not exist. Therefore it was skipped.",
+                link.source, key
+            ));
+            continue;
+        }
+        let confine = |target: &str, source: &str| -> Result<()> {
+            if link.allow_outside_root {
+                return Ok(());
+            }
+            // a mirror replaces the files below its target directory, so the directory itself is resolved
+            let target_is_replaced = !(link.mirror && target == key);
+            for (kind, path, is_target) in [
+                ("target", target, target_is_replaced),
+                ("source", source, false),
+            ] {
+                if !is_within(path, current_dir, is_target) {
+                    return Err(Error::InvalidLink {
+                        link: key.clone(),
+                        activate_file: activate_file.clone(),
+                        message: format!("The {} `{}` is outside of the `activate.toml` directory. Set `allow_outside_root = true` to allow it.", kind, path),
+                    });
+                }
+            }
+            Ok(())
+        };
+        confine(key, &link.source)?;
+        let links = expand_link(key, link, current_dir)?;
+        // the expanded files may still lead outside through symlinks below the declared directories
+        for (target, file_link) in links.iter() {
+            confine(target, &file_link.source)?;
+            validate_link(target, file_link, current_dir)?;
+        }
+        if links.is_empty() && link.optional {
+            warn(format!(
+                "The source `{}` of the optional link `{}` matched nothing. Therefore it was skipped.",
+                link.source, key
+            ));
+            continue;
+        }
+        if links.is_empty() {
+            return Err(Error::MissingSource {
+                link: key.clone(),
+                path: current_dir.join(&link.source),
+                activate_file,
+            });
+        }
+        expanded.extend(links);
+    }
+    expanded.sort_by(|(target1, _), (target2, _)| target1.cmp(target2));
+    if let Some(duplicate) = expanded.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+        return Err(Error::InvalidLink {
+            link: duplicate[0].0.clone(),
+            activate_file,
+            message: "The target is linked more than once.".to_string(),
+        });
+    }
+    Ok(expanded)
+}
+
+/// Expands the glob or `mirror` link at `key` into a link for each file. A glob that matches nothing is expanded to no
+/// links.
+pub(crate) fn expand_link(
+    key: &str,
+    link: &Link,
+    current_dir: &Path,
+) -> Result<Vec<(String, Link)>> {
+    let invalid = |message: String| Error::InvalidLink {
+        link: key.to_string(),
+        activate_file: current_dir.join(ACTIVATE_TOML),
+        message,
+    };
+    let mut expanded = Vec::new();
+    if link.mirror {
+        let source = current_dir.join(&link.source);
+        if !source.is_dir() {
+            return Err(invalid(format!(
+                "The source `{}` of a mirror should be a directory.",
+                link.source
+            )));
+        }
+        for file in files_below(&source, Path::new(""))? {
+            let file = file.to_string_lossy();
+            let mut file_link = link.clone();
+            file_link.source = format!("{}/{}", link.source.trim_end_matches('/'), file);
+            file_link.mirror = false;
+            expanded.push((format!("{}/{}", key.trim_end_matches('/'), file), file_link));
+        }
+    } else if is_pattern(&link.source) {
+        if wildcards(key) != wildcards(&link.source) {
+            return Err(invalid(format!(
+                "The target and the source `{}` should have the same wildcards.",
+                link.source
+            )));
+        }
+        for (source, captures) in glob(current_dir, &link.source)? {
+            let mut captures = captures.into_iter();
+            let target = key
+                .chars()
+                .map(|c| match c {
+                    '*' | '?' => captures.next().unwrap(),
+                    c => c.to_string(),
+                })
+                .collect();
+            let mut file_link = link.clone();
+            file_link.source = source;
+            expanded.push((target, file_link));
+        }
+    } else {
+        expanded.push((key.to_string(), link.clone()));
+    }
+    Ok(expanded)
+}
+
+/// The paths of every file below `dir`, relative to it and prefixed with `relative`.
+fn files_below(dir: &Path, relative: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries = fs::read_dir(dir)
+        .and_then(|entries| entries.collect::<std::io::Result<Vec<_>>>())
+        .context(|| format!("Could not read `{}` directory.", dir.display()))?;
+    entries.sort_by_key(|entry| entry.file_name());
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = relative.join(entry.file_name());
+        if entry.path().is_dir() {
+            files.extend(files_below(&entry.path(), &path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+fn is_pattern(path: &str) -> bool {
+    path.contains(['*', '?'])
+}
+
+fn wildcards(path: &str) -> Vec<char> {
+    path.chars().filter(|c| matches!(c, '*' | '?')).collect()
+}
+
+/// The paths, relative to `dir`, matching `pattern`, with the text matched by each wildcard. `*` matches any part of
+/// a file name and `?` a single character, neither matches `/`. Hidden files only match if the pattern starts with a
+/// `.`.
+fn glob(dir: &Path, pattern: &str) -> Result<Vec<(String, Vec<String>)>> {
+    let mut matches = vec![(String::new(), Vec::new())];
+    for component in pattern.split('/').filter(|component| !component.is_empty()) {
+        let mut next = Vec::new();
+        for (path, captures) in matches {
+            let join = |name: &str| {
+                if path.is_empty() {
+                    name.to_string()
+                } else {
+                    format!("{}/{}", path, name)
+                }
+            };
+            if !is_pattern(component) {
+                next.push((join(component), captures));
+                continue;
+            }
+            let parent = dir.join(&path);
+            if !parent.is_dir() {
+                continue;
+            }
+            let mut names = fs::read_dir(&parent)
+                .and_then(|entries| entries.collect::<std::io::Result<Vec<_>>>())
+                .context(|| format!("Could not read `{}` directory.", parent.display()))?
+                .into_iter()
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+            names.sort();
+            for name in names {
+                if name.starts_with('.') && !component.starts_with('.') {
+                    continue;
+                }
+                let pattern = component.chars().collect::<Vec<_>>();
+                let name_chars = name.chars().collect::<Vec<_>>();
+                if let Some(mut name_captures) = wildcard_match(&pattern, &name_chars) {
+                    let mut captures = captures.clone();
+                    captures.append(&mut name_captures);
+                    next.push((join(&name), captures));
+                }
+            }
+        }
+        matches = next;
+    }
+    matches.retain(|(path, _)| dir.join(path).exists());
+    Ok(matches)
+}
+
+/// The text matched by each wildcard of `pattern` in `name`, if it matches.
+fn wildcard_match(pattern: &[char], name: &[char]) -> Option<Vec<String>> {
+    match pattern.split_first() {
+        None => name.is_empty().then(Vec::new),
+        Some(('?', pattern)) => {
+            let (c, name) = name.split_first()?;
+            let mut captures = wildcard_match(pattern, name)?;
+            captures.insert(0, c.to_string());
+            Some(captures)
+        }
+        Some(('*', pattern)) => (0..=name.len()).find_map(|len| {
+            let mut captures = wildcard_match(pattern, &name[len..])?;
+            captures.insert(0, name[..len].iter().collect());
+            Some(captures)
+        }),
+        Some((c, pattern)) => {
+            let (n, name) = name.split_first()?;
+            if c == n {
+                wildcard_match(pattern, name)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// Creates `links`. Existing targets in `replaced_links` are replaced atomically, by creating the link under a
+/// temporary name and renaming it over the target.
+pub(crate) fn add_links(
+    links: &[(String, Link)],
+    replaced_links: &HashMap<String, Vec<String>>,
+    current_links_file: &Path,
+    current_dir: &Path,
+) -> Result<()> {
+    let activate_file = current_dir.join(ACTIVATE_TOML);
+    // The replaced links are still recorded, until they are replaced.
+    let mut active_links = if current_links_file.exists() {
+        read_links(current_links_file)?.0.unwrap_or_default()
+    } else {
+        HashMap::new()
+    };
+    for (key, link) in links {
+        let value = &link.source;
+        let source = link_path(value, current_dir);
+        let target = link_path(key, current_dir);
+        let replaced_dirs = replaced_links.get(key);
+        if replaced_dirs.is_none() && (target.exists() || target.is_symlink()) {
+            return Err(Error::TargetExists {
+                link: key.clone(),
+                path: target,
+                activate_file,
+            });
+        }
+        let checksum = match link.mode {
+            LinkMode::Symlink | LinkMode::Hardlink => None,
+            LinkMode::Copy => Some(checksum(&source)?),
+        };
+        let dirs = match replaced_dirs {
+            Some(dirs) => dirs.clone(),
+            None => {
+                let mut dirs = Path::new(key)
+                    .ancestors()
+                    .skip(1)
+                    .filter(|dir| !dir.as_os_str().is_empty() && !current_dir.join(dir).exists())
+                    .map(|dir| dir.to_string_lossy().into_owned())
+                    .collect::<Vec<_>>();
+                dirs.reverse();
+                dirs
+            }
+        };
+        let active_link = ActiveLink {
+            source: value.clone(),
+            mode: link.mode,
+            checksum,
+            dirs,
+            allow_outside_root: link.allow_outside_root,
+        };
+        if let Some(parent) = active_link.dirs.last() {
+            let parent = current_dir.join(parent);
+            fs::create_dir_all(&parent)
+                .context(|| format!("Could not create `{}` directory.", parent.display()))?;
+        }
+        let link_path = if replaced_dirs.is_some() {
+            let temporary = temporary_path(&target);
+            // Left over from an interrupted activation.
+            if temporary.exists() || temporary.is_symlink() {
+                remove_path(&temporary)
+                    .context(|| format!("Could not remove `{}`.", temporary.display()))?;
+            }
+            temporary
+        } else {
+            target.clone()
+        };
+        let created = match link.mode {
+            LinkMode::Symlink => create_symlink(key, value, &link_path, current_dir),
+            LinkMode::Hardlink => fs::hard_link(&source, &link_path).context(|| {
+                format!(
+                    "Could not hardlink entity `{}` to `{}`, in directory `{}`.",
+                    &key,
+                    &value,
+                    current_dir.to_string_lossy()
+                )
+            }),
+            LinkMode::Copy => copy_all(&source, &link_path),
+        }
+        .and_then(|()| {
+            if link_path == target {
+                return Ok(());
+            }
+            // A directory can only be renamed over an empty directory, and nothing but a directory can be renamed
+            // over one, so directories are not replaced atomically.
+            let is_dir = |path: &Path| path.is_dir() && !path.is_symlink();
+            if is_dir(&link_path) || is_dir(&target) {
+                remove_path(&target)
+                    .context(|| format!("Could not remove link `{}`.", target.display()))?;
+            }
+            fs::rename(&link_path, &target).context(|| {
+                format!(
+                    "Could not replace link `{}` with `{}`.",
+                    target.display(),
+                    link_path.display()
+                )
+            })
+        });
+        if let Err(err) = created {
+            if link_path != target && (link_path.exists() || link_path.is_symlink()) {
+                // The error is more telling than a failure to clean up.
+                let _ = remove_path(&link_path);
+            }
+            return Err(err);
+        }
+        // Only recorded once created, so the state never holds a link that is not there.
+        active_links.insert(key.clone(), active_link);
+        write_links(current_links_file, &active_links)?;
+    }
+    Ok(())
+}
+
+/// Checks that the source of the link at `key` exists and that the link can be created, before anything is
+/// deactivated.
+fn validate_link(key: &str, link: &Link, current_dir: &Path) -> Result<()> {
+    let activate_file = current_dir.join(ACTIVATE_TOML);
+    for (kind, path) in [("source", link.source.as_str()), ("target", key)] {
+        let path = Path::new(path);
+        if !link.allow_outside_root && (path.starts_with("./") || path.starts_with("../")) {
+            return Err(Error::InvalidLink {
+                link: key.to_string(),
+                activate_file,
+                message: format!("The {} `{}` should not start with `./` or `../`. The {} is relative to the `activate.toml` directory and below.", kind, path.to_string_lossy(), kind),
+            });
+        }
+    }
+    let source = link_path(&link.source, current_dir);
+    if !source.exists() {
+        return Err(Error::MissingSource {
+            link: key.to_string(),
+            path: source,
+            activate_file,
+        });
+    }
+    if link.mode == LinkMode::Hardlink && source.is_dir() {
+        return Err(Error::InvalidLink {
+            link: key.to_string(),
+            activate_file,
+            message: "Directories can not be hardlinked.".to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// The path of the link target or source `path`, relative to `current_dir`.
+fn link_path(path: &str, current_dir: &Path) -> PathBuf {
+    let path = current_dir.join(path);
+    match path.strip_prefix("./") {
+        Ok(path) => path.to_path_buf(),
+        Err(_) => path,
+    }
+}
+
+/// Removes the file, symlink or directory at `path`.
+fn remove_path(path: &Path) -> std::io::Result<()> {
+    if path.is_dir() && !path.is_symlink() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// A path next to `target` to create its replacement at.
+fn temporary_path(target: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(target.file_name().unwrap_or_default());
+    name.push(".activate-tmp");
+    target.with_file_name(name)
+}
+
+/// Where the symlink at `key` to the source `value` points. Relative to the symlink, unless either is outside of
+/// `current_dir` or absolute.
+pub(crate) fn symlink_destination(key: &str, value: &str, current_dir: &Path) -> PathBuf {
+    match normalize(key) {
+        Some(key) if !Path::new(value).is_absolute() => {
+            let depth_adjustment = key
+                .components()
+                .skip(1)
+                .fold(PathBuf::new(), |p, _| p.join(".."));
+            depth_adjustment.join(value)
+        }
+        _ => {
+            std::path::absolute(current_dir.join(value)).unwrap_or_else(|_| current_dir.join(value))
+        }
+    }
+}
+
+/// `path` with its `.` and `..` components resolved, if it stays within the directory it is relative to.
+pub(crate) fn normalize(path: &str) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(normalized)
+}
+
+/// Whether `path`, relative to `current_dir`, stays within `current_dir`. Checked lexically, and then with the symlinks
+/// of its deepest existing ancestor resolved, so a symlinked directory can not lead outside. A target is replaced, so
+/// only its parent is resolved.
+pub(crate) fn is_within(path: &str, current_dir: &Path, is_target: bool) -> bool {
+    let Some(normalized) = normalize(path) else {
+        return false;
+    };
+    let Ok(root) = current_dir.canonicalize() else {
+        return false;
+    };
+    let path = root.join(normalized);
+    let resolved = path
+        .ancestors()
+        .skip(if is_target { 1 } else { 0 })
+        .find(|ancestor| ancestor.exists())
+        .and_then(|ancestor| ancestor.canonicalize().ok());
+    resolved.is_some_and(|resolved| resolved.starts_with(&root))
+}
+
+fn create_symlink(key: &str, value: &str, target: &Path, current_dir: &Path) -> Result<()> {
+    let link_path = symlink_destination(key, value, current_dir);
+    // #[cfg(windows)]
+    // {
+    //     let metadata = fs::symlink_metadata(&value)
+    //         .context(|| format!("Could not get metadata for `{}`.", &key))?;
+    //     if metadata.is_dir() {
+    //         std::os::windows::fs::symlink_any(link_path, target).context(|| format!(
+    //             "Could not link entity `{}` to `{}`, in directory `{}`.",
+    //             &key,
+    //             &value,
+    //             current_dir.to_string_lossy()
+    //         ))?;
+    //     } else {
+    //         std::os::windows::fs::symlink_file(link_path, target).context(|| format!(
+    //             "Could not link entity `{}` to `{}`, in directory `{}`.",
+    //             &key,
+    //             &value,
+    //             current_dir.to_string_lossy()
+    //         ))?;
+    //     }
+    // }
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(link_path, target).context(|| {
+        format!(
+            "Could not link entity `{}` to `{}`, in directory `{}`.",
+            &key,
+            &value,
+            current_dir.to_string_lossy()
+        )
+    })?;
+    Ok(())
+}
+
+/// Removes the links in `current_links_file`, except those at `kept_targets`. Returns the kept links, mapped to the
+/// directories created for them.
+///
+/// The kept links stay in the links state, until they are replaced. Links that were changed since they were created are
+/// left in place, and in the links state, with a warning, unless `force` is set.
+pub(crate) fn remove_links(
+    current_links_file: &Path,
+    current_dir: &Path,
+    kept_targets: &HashSet<&str>,
+    force: bool,
+) -> Result<HashMap<String, Vec<String>>> {
+    let links = read_links(current_links_file)?;
+    let mut created_dirs = Vec::new();
+    let mut kept_links = HashMap::new();
+    let mut remaining_links = HashMap::new();
+    if let Some(links) = links.0 {
+        for (key, link) in links {
+            if !link.allow_outside_root && !is_within(&key, current_dir, true) {
+                return Err(Error::StateCorruption {
+                    file: current_links_file.to_path_buf(),
+                    message: format!(
+                        "The link `{}` is outside of the `activate.toml` directory. Therefore it will not be removed.",
+                        key
+                    ),
+                });
+            }
+            let target = current_dir.join(&key);
+            // Unlike `exists`, does not follow the link, so dangling symlinks are found too.
+            let Ok(metadata) = fs::symlink_metadata(&target) else {
+                created_dirs.extend(link.dirs);
+                continue;
+            };
+            let file_type = metadata.file_type();
+            let tampered = match link.mode {
+                LinkMode::Symlink if !file_type.is_symlink() => Some("is not a symlink anymore"),
+                LinkMode::Symlink
+                    if fs::read_link(&target).ok()
+                        != Some(symlink_destination(&key, &link.source, current_dir)) =>
+                {
+                    Some("points somewhere other than where it was created")
+                }
+                LinkMode::Hardlink if !file_type.is_file() => Some("is not a file anymore"),
+                LinkMode::Copy if link.checksum != Some(checksum(&target)?) => {
+                    Some("was modified locally")
+                }
+                _ => None,
+            };
+            if let Some(change) = tampered {
+                if !force {
+                    warn(format!(
+                        "The link `{}` {}. Therefore it was not removed. Use `--force` to remove it.",
+                        target.to_string_lossy(),
+                        change
+                    ));
+                    remaining_links.insert(key, link);
+                    continue;
+                }
+            } else if kept_targets.contains(key.as_str()) {
+                // Stays recorded until the next environment replaces it.
+                kept_links.insert(key.clone(), link.dirs.clone());
+                remaining_links.insert(key, link);
+                continue;
+            }
+            created_dirs.extend(link.dirs);
+            if file_type.is_dir() {
+                fs::remove_dir_all(&target)
+            } else {
+                fs::remove_file(&target)
+            }
+            .context(|| format!("Could not remove link `{}`.", target.to_string_lossy()))?;
+        }
+    }
+    // Deepest first, so a directory is empty once the directories created in it are removed.
+    created_dirs.sort_by_key(|dir| std::cmp::Reverse(Path::new(dir).components().count()));
+    for dir in created_dirs {
+        let dir = current_dir.join(dir);
+        let is_empty = fs::read_dir(&dir).is_ok_and(|mut entries| entries.next().is_none());
+        if is_empty {
+            fs::remove_dir(&dir)
+                .context(|| format!("Could not remove `{}` directory.", dir.display()))?;
+        }
+    }
+
+    if remaining_links.is_empty() {
+        fs::remove_file(current_links_file).context(|| {
+            format!(
+                "Could not remove `{}` file. Links are still active.",
+                current_links_file.display()
+            )
+        })?;
+    } else {
+        write_links(current_links_file, &remaining_links)?;
+    }
+    Ok(kept_links)
+}
+
+/// Replaces the links state in `current_links_file` with `links`.
+pub(crate) fn write_links(
+    current_links_file: &Path,
+    links: &HashMap<String, ActiveLink>,
+) -> Result<()> {
+    let mut keys = links.keys().collect::<Vec<_>>();
+    keys.sort();
+    let entries = keys
+        .into_iter()
+        .map(|key| links[key].to_entry(key))
+        .collect::<String>();
+    fs::write(current_links_file, entries).context(|| {
+        format!(
+            "Could not write to `{}` file.",
+            current_links_file.display()
+        )
+    })
+}
+
+/// Copies the file or directory `source` to `target`.
+fn copy_all(source: &Path, target: &Path) -> Result<()> {
+    if source.is_dir() {
+        fs::create_dir(target)
+            .context(|| format!("Could not create `{}` directory.", target.display()))?;
+        let entries = fs::read_dir(source)
+            .context(|| format!("Could not read `{}` directory.", source.display()))?;
+        for entry in entries {
+            let entry =
+                entry.context(|| format!("Could not read `{}` directory.", source.display()))?;
+            copy_all(&entry.path(), &target.join(entry.file_name()))?;
+        }
+    } else {
+        fs::copy(source, target).context(|| {
+            format!(
+                "Could not copy `{}` to `{}`.",
+                source.display(),
+                target.display()
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// The sha256 checksum of the file, or the relative paths and contents of every file in the directory, `path`.
+fn checksum(path: &Path) -> Result<String> {
+    fn hash(path: &Path, relative: &Path, hasher: &mut Sha256) -> Result<()> {
+        if path.is_dir() {
+            let mut entries = fs::read_dir(path)
+                .and_then(|entries| entries.collect::<std::io::Result<Vec<_>>>())
+                .context(|| format!("Could not read `{}` directory.", path.display()))?;
+            entries.sort_by_key(|entry| entry.file_name());
+            for entry in entries {
+                hash(&entry.path(), &relative.join(entry.file_name()), hasher)?;
+            }
+        } else {
+            let contents =
+                fs::read(path).context(|| format!("Could not read `{}` file.", path.display()))?;
+            hasher.update(relative.to_string_lossy().as_bytes());
+            hasher.update([0]);
+            hasher.update(&contents);
+        }
+        Ok(())
+    }
+    let mut hasher = Sha256::new();
+    hash(path, Path::new(""), &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub(crate) fn read_links(current_links_file: &Path) -> Result<ActiveEnvironmentLinks> {
+    let links_string = fs::read_to_string(current_links_file)
+        .context(|| format!("Could not read `{}` file.", current_links_file.display()))?;
+    toml::from_str::<ActiveEnvironmentLinks>(&links_string).map_err(|err| {
+        let (line, column) = line_column(&links_string, err.span());
+        Error::StateCorruption {
+            file: current_links_file.to_path_buf(),
+            message: format!(
+                "Could not parse `{}` at line {}, column {}. {}",
+                STATE_LINKS_FILE,
+                line,
+                column,
+                err.message()
+            ),
+        }
+    })
+}
+
+//************************************************************************//
+
+/// Renders each template with `env` to its target path, recording it in `current_templates_file`.
+pub(crate) fn add_templates(
+    templates: &HashMap<String, Template>,
+    env: &HashMap<String, String>,
+    has_secrets: bool,
+    current_templates_file: &Path,
+    current_dir: &Path,
+) -> Result<()> {
+    let activate_file = current_dir.join(ACTIVATE_TOML);
+    let mut templates_file = File::options()
+        .create(true)
+        .append(true)
+        .open(current_templates_file)
+        .context(|| {
+            format!(
+                "Could not open `{}` file.",
+                current_templates_file.display()
+            )
+        })?;
+    for (key, template) in templates {
+        if !template.allow_outside_root {
+            for (kind, path, is_target) in [
+                ("template", &template.template, false),
+                ("target", key, true),
+            ] {
+                if !is_within(path, current_dir, is_target) {
+                    return Err(Error::InvalidTemplate {
+                        target: key.clone(),
+                        activate_file,
+                        message: format!("The {} `{}` is outside of the `activate.toml` directory. Set `allow_outside_root = true` to allow it.", kind, path),
+                    });
+                }
+            }
+        }
+        let source = current_dir.join(&template.template);
+        if !source.is_file() {
+            return Err(Error::MissingTemplate {
+                target: key.clone(),
+                path: source,
+                activate_file,
+            });
+        }
+        let target = current_dir.join(key);
+        if target.exists() || target.is_symlink() {
+            return Err(Error::TemplateTargetExists {
+                target: key.clone(),
+                path: target,
+                activate_file,
+            });
+        }
+        let contents = fs::read_to_string(&source)
+            .context(|| format!("Could not read `{}` file.", source.display()))?;
+        let rendered = template::render(&source, &contents, env)?;
+        if let Some(dir) = target.parent() {
+            fs::create_dir_all(dir)
+                .context(|| format!("Could not create `{}` directory.", dir.display()))?;
+        }
+        let mut options = File::options();
+        options.create_new(true).write(true);
+        #[cfg(unix)]
+        if has_secrets {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options
+            .open(&target)
+            .and_then(|mut file| file.write_all(rendered.as_bytes()))
+            .context(|| format!("Could not write to `{}` file.", target.display()))?;
+        let active_template = ActiveTemplate {
+            template: template.template.clone(),
+            checksum: Some(checksum(&target)?),
+            allow_outside_root: template.allow_outside_root,
+        };
+        templates_file
+            .write_all(active_template.to_entry(key).as_bytes())
+            .context(|| {
+                format!(
+                    "Could not write to `{}` file.",
+                    current_templates_file.display()
+                )
+            })?;
+    }
+    Ok(())
+}
+
+/// Removes the rendered templates in `current_templates_file`. Rendered files that were modified since they were
+/// rendered are left in place, and in the templates state, with a warning, unless `force` is set.
+pub(crate) fn remove_templates(
+    current_templates_file: &Path,
+    current_dir: &Path,
+    force: bool,
+) -> Result<()> {
+    let templates = read_templates(current_templates_file)?;
+    let mut modified_templates = HashMap::new();
+    for (key, template) in templates.0.unwrap_or_default() {
+        if !template.allow_outside_root && !is_within(&key, current_dir, true) {
+            return Err(Error::StateCorruption {
+                file: current_templates_file.to_path_buf(),
+                message: format!(
+                    "The rendered template `{}` is outside of the `activate.toml` directory. Therefore it will not be removed.",
+                    key
+                ),
+            });
+        }
+        let target = current_dir.join(&key);
+        if !target.is_file() {
+            continue;
+        }
+        let modified = template
+            .checksum
+            .as_ref()
+            .is_some_and(|recorded| Some(recorded) != checksum(&target).ok().as_ref());
+        if modified && !force {
+            warn(format!(
+                "The rendered template `{}` was modified locally. Therefore it was not removed. Use `--force` to remove it.",
+                target.to_string_lossy()
+            ));
+            modified_templates.insert(key, template);
+            continue;
+        }
+        fs::remove_file(&target)
+            .context(|| format!("Could not remove rendered template `{}`.", target.display()))?;
+    }
+    if modified_templates.is_empty() {
+        fs::remove_file(current_templates_file).context(|| {
+            format!(
+                "Could not remove `{}` file. Templates are still active.",
+                current_templates_file.display()
+            )
+        })
+    } else {
+        write_templates(current_templates_file, &modified_templates)
+    }
+}
+
+/// Replaces the templates state in `current_templates_file` with `templates`, mapping targets to templates.
+pub(crate) fn write_templates(
+    current_templates_file: &Path,
+    templates: &HashMap<String, ActiveTemplate>,
+) -> Result<()> {
+    let mut targets = templates.keys().collect::<Vec<_>>();
+    targets.sort();
+    let entries = targets
+        .into_iter()
+        .map(|target| templates[target].to_entry(target))
+        .collect::<String>();
+    fs::write(current_templates_file, entries).context(|| {
+        format!(
+            "Could not write to `{}` file.",
+            current_templates_file.display()
+        )
+    })
+}
+
+pub(crate) fn read_templates(current_templates_file: &Path) -> Result<ActiveEnvironmentTemplates> {
+    let templates_string = fs::read_to_string(current_templates_file).context(|| {
+        format!(
+            "Could not read `{}` file.",
+            current_templates_file.display()
+        )
+    })?;
+    toml::from_str::<ActiveEnvironmentTemplates>(&templates_string).map_err(|err| {
+        let (line, column) = line_column(&templates_string, err.span());
+        Error::StateCorruption {
+            file: current_templates_file.to_path_buf(),
+            message: format!(
+                "Could not parse `{}` at line {}, column {}. {}",
+                STATE_TEMPLATES_FILE,
+                line,
+                column,
+                err.message()
+            ),
+        }
+    })
+}
@@ -0,0 +1,215 @@
+use std::{collections::HashMap, ops::Range, path::Path};
+
+use crate::error::{Error, Result};
+
+const FILTERS: [&str; 4] = ["upper", "lower", "json", "shell-quote"];
+
+/// Renders the template `source`, read from `file`, with the env variables `env`.
+///
+/// `{{ KEY }}` is replaced with the value of `KEY`, passed through any filters, e.g. `{{ KEY | upper | json }}`.
+/// `{% for key, value in env %}...{% endfor %}` repeats its body for each env variable, sorted by key. A newline
+/// directly after a `{% ... %}` tag is removed.
+pub(crate) fn render(file: &Path, source: &str, env: &HashMap<String, String>) -> Result<String> {
+    let nodes =
+        parse(source).map_err(|(span, message)| Error::parse(file, source, Some(span), message))?;
+    let mut vars = Vec::new();
+    let mut rendered = String::with_capacity(source.len());
+    render_nodes(&nodes, env, &mut vars, &mut rendered)
+        .map_err(|(span, message)| Error::parse(file, source, Some(span), message))?;
+    Ok(rendered)
+}
+
+/// A template error, at a span of the template source.
+type TemplateResult<T> = std::result::Result<T, (Range<usize>, String)>;
+
+#[derive(Debug)]
+enum Node<'a> {
+    Text(&'a str),
+    Var {
+        name: &'a str,
+        filters: Vec<&'a str>,
+        span: Range<usize>,
+    },
+    For {
+        key: &'a str,
+        value: Option<&'a str>,
+        body: Vec<Node<'a>>,
+    },
+}
+
+fn parse(source: &str) -> TemplateResult<Vec<Node<'_>>> {
+    let mut nodes = Vec::new();
+    let mut loops: Vec<OpenLoop> = Vec::new();
+    let mut pos = 0;
+    while let Some(offset) = source[pos..].find('{') {
+        let start = pos + offset;
+        let close = match &source[start..] {
+            tag if tag.starts_with("{{") => "}}",
+            tag if tag.starts_with("{%") => "%}",
+            _ => {
+                current(&mut nodes, &mut loops).push(Node::Text(&source[pos..start + 1]));
+                pos = start + 1;
+                continue;
+            }
+        };
+        if start > pos {
+            current(&mut nodes, &mut loops).push(Node::Text(&source[pos..start]));
+        }
+        let Some(end) = source[start..].find(close).map(|end| start + end + 2) else {
+            return Err((
+                start..start + 2,
+                format!("Unclosed tag, expected `{}`.", close),
+            ));
+        };
+        let span = start..end;
+        let inner = source[start + 2..end - 2].trim();
+        pos = end;
+
+        if close == "}}" {
+            let mut parts = inner.split('|').map(str::trim);
+            let name = parts.next().unwrap();
+            if !is_identifier(name) {
+                return Err((span, format!("`{}` is not a valid variable name.", name)));
+            }
+            let filters = parts.collect::<Vec<_>>();
+            if let Some(filter) = filters.iter().find(|filter| !FILTERS.contains(filter)) {
+                return Err((
+                    span,
+                    format!(
+                        "Unknown filter `{}`. Expected one of {}.",
+                        filter,
+                        FILTERS.join(", ")
+                    ),
+                ));
+            }
+            current(&mut nodes, &mut loops).push(Node::Var {
+                name,
+                filters,
+                span,
+            });
+            continue;
+        }
+
+        if source[pos..].starts_with('\n') {
+            pos += 1;
+        }
+        if inner == "endfor" {
+            let Some(OpenLoop {
+                key, value, body, ..
+            }) = loops.pop()
+            else {
+                return Err((span, "`endfor` without a `for`.".to_string()));
+            };
+            current(&mut nodes, &mut loops).push(Node::For { key, value, body });
+        } else if let Some(for_loop) = inner.strip_prefix("for ") {
+            let Some((names, iterable)) = for_loop.split_once(" in ") else {
+                return Err((span, "Expected `for key, value in env`.".to_string()));
+            };
+            if iterable.trim() != "env" {
+                return Err((
+                    span,
+                    format!("Can only loop over `env`, not `{}`.", iterable.trim()),
+                ));
+            }
+            let mut names = names.split(',').map(str::trim);
+            let key = names.next().unwrap();
+            let value = names.next();
+            if names.next().is_some() || !is_identifier(key) || !value.is_none_or(is_identifier) {
+                return Err((span, "Expected `for key, value in env`.".to_string()));
+            }
+            loops.push(OpenLoop {
+                key,
+                value,
+                span,
+                body: Vec::new(),
+            });
+        } else {
+            return Err((span, format!("Unknown tag `{}`.", inner)));
+        }
+    }
+    if pos < source.len() {
+        current(&mut nodes, &mut loops).push(Node::Text(&source[pos..]));
+    }
+    if let Some(open_loop) = loops.pop() {
+        return Err((open_loop.span, "`for` without an `endfor`.".to_string()));
+    }
+    Ok(nodes)
+}
+
+/// A `for` loop whose `endfor` is not parsed yet.
+struct OpenLoop<'a> {
+    key: &'a str,
+    value: Option<&'a str>,
+    span: Range<usize>,
+    body: Vec<Node<'a>>,
+}
+
+/// The nodes of the innermost open loop, or the top level nodes.
+fn current<'b, 'a>(
+    nodes: &'b mut Vec<Node<'a>>,
+    loops: &'b mut [OpenLoop<'a>],
+) -> &'b mut Vec<Node<'a>> {
+    match loops.last_mut() {
+        Some(open_loop) => &mut open_loop.body,
+        None => nodes,
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Renders `nodes` to `rendered`. `vars` are the variables of the enclosing loops, innermost last.
+fn render_nodes<'a>(
+    nodes: &[Node<'a>],
+    env: &'a HashMap<String, String>,
+    vars: &mut Vec<(&'a str, &'a str)>,
+    rendered: &mut String,
+) -> TemplateResult<()> {
+    for node in nodes {
+        match node {
+            Node::Text(text) => rendered.push_str(text),
+            Node::Var {
+                name,
+                filters,
+                span,
+            } => {
+                let value = vars
+                    .iter()
+                    .rev()
+                    .find(|(var, _)| var == name)
+                    .map(|(_, value)| *value)
+                    .or_else(|| env.get(*name).map(String::as_str))
+                    .ok_or_else(|| (span.clone(), format!("`{}` is not defined.", name)))?;
+                let value = filters.iter().fold(value.to_string(), |value, filter| {
+                    apply_filter(filter, &value)
+                });
+                rendered.push_str(&value);
+            }
+            Node::For { key, value, body } => {
+                let mut entries = env.iter().collect::<Vec<_>>();
+                entries.sort();
+                for (k, v) in entries {
+                    let scope = vars.len();
+                    vars.push((key, k));
+                    if let Some(value) = value {
+                        vars.push((value, v));
+                    }
+                    render_nodes(body, env, vars, rendered)?;
+                    vars.truncate(scope);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn apply_filter(filter: &str, value: &str) -> String {
+    match filter {
+        "upper" => value.to_uppercase(),
+        "lower" => value.to_lowercase(),
+        "json" => serde_json::to_string(value).expect("Could not serialize value to json."),
+        "shell-quote" => format!("'{}'", value.replace('\'', "'\\''")),
+        _ => unreachable!("filters are validated when parsing"),
+    }
+}
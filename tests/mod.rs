@@ -1,10 +1,17 @@
-use std::{collections::HashMap, env, fs, path::Path, sync::Once};
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+    sync::Once,
+};
 
+use activate::{ActivateOptions, Error, Workspace};
 use assert_cmd::cargo::CargoError;
-use predicates::prelude::predicate;
+use predicates::prelude::{predicate, PredicateBooleanExt};
 
 static INIT: Once = Once::new();
 
+#[allow(clippy::needless_borrows_for_generic_args)]
 pub fn initialize() {
     INIT.call_once(|| {
         let test_dir = Path::new("tests");
@@ -21,6 +28,21 @@ pub fn initialize() {
     assert.success().stdout(predicate::str::contains(""));
 }
 
+/// Creates a fresh project tree, outside of the `tests` directory, from `(path, contents)` pairs.
+pub fn create_project(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let root = env::temp_dir().join("activate-tests").join(name);
+    if root.exists() {
+        fs::remove_dir_all(&root).unwrap();
+    }
+    fs::create_dir_all(&root).unwrap();
+    for (path, contents) in files {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+    root
+}
+
 //************************************************************************//
 
 #[test]
@@ -90,6 +112,7 @@ fn links_switching_between() -> Result<(), CargoError> {
 }
 
 #[test]
+#[allow(clippy::unnecessary_get_then_check)]
 fn env_switching_between() -> Result<(), CargoError> {
     initialize();
 
@@ -227,11 +250,11 @@ export TEST_ENV3=test3
         fs::read_to_string(Path::new(".activate/active/.env")).unwrap(),
         r#"# Generated - managed by `activate`.
 
-DJANGO_SETTINGS_MODULE=settings
-PYTHONPATH=src
-TEST_ENV=test
-TEST_ENV2=test2
-TEST_ENV3=test3
+DJANGO_SETTINGS_MODULE="settings"
+PYTHONPATH="src"
+TEST_ENV="test"
+TEST_ENV2="test2"
+TEST_ENV3="test3"
 "#
     );
 
@@ -239,9 +262,9 @@ TEST_ENV3=test3
         fs::read_to_string(Path::new("another_active_dir/.activate/active/.env")).unwrap(),
         r#"# Generated - managed by `activate`.
 
-TEST_ENV=test
-TEST_ENV2=test2
-TEST_ENV3=test3
+TEST_ENV="test"
+TEST_ENV2="test2"
+TEST_ENV3="test3"
 "#
     );
 
@@ -249,7 +272,7 @@ TEST_ENV3=test3
         fs::read_to_string(Path::new("another_active_dir/another_active_dir/.activate/active/.env")).unwrap(),
         r#"# Generated - managed by `activate`.
 
-TEST_ENV3=test3
+TEST_ENV3="test3"
 "#
     );
 
@@ -312,3 +335,1855 @@ fn env_file_is_valid_link() -> Result<(), CargoError> {
 
     Ok(())
 }
+
+#[test]
+fn descendant_collisions() -> Result<(), CargoError> {
+    let root = create_project(
+        "descendant_collisions",
+        &[
+            ("activate.toml", "[dev.env]\nPORT = \"80\"\n"),
+            (
+                "api/activate.toml",
+                "prefix = \"BACKEND_\"\n[dev.env]\nPORT = \"3000\"\n",
+            ),
+            ("web/activate.toml", "[dev.env]\nPORT = \"8080\"\n"),
+        ],
+    );
+
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .arg("-d")
+        .assert();
+    assert
+        .failure()
+        .stderr(predicate::str::contains("Environment variable collision"));
+
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .arg("-d")
+        .arg("--collision")
+        .arg("prefix")
+        .assert();
+    assert.success().stdout(predicate::str::contains(
+        r#"export BACKEND_PORT=3000
+export PORT=80
+export WEB_PORT=8080
+"#,
+    ));
+    assert_eq!(
+        fs::read_to_string(root.join("api/.activate/active/.env")).unwrap(),
+        r#"# Generated - managed by `activate`.
+
+PORT="3000"
+"#
+    );
+
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .arg("-d")
+        .arg("--collision")
+        .arg("parent-wins")
+        .assert();
+    assert
+        .success()
+        .stdout(predicate::str::contains("export PORT=80\n"));
+
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .arg("-d")
+        .arg("--collision")
+        .arg("child-wins")
+        .assert();
+    // `api` and `web` are siblings, so neither is preferred
+    assert
+        .failure()
+        .stderr(predicate::str::contains("Environment variable collision"));
+
+    fs::write(root.join("web/activate.toml"), "[dev.env]\n").unwrap();
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .arg("-d")
+        .arg("--collision")
+        .arg("child-wins")
+        .assert();
+    assert
+        .success()
+        .stdout(predicate::str::contains("export PORT=3000\n"));
+
+    // deactivating unsets the keys merged with the strategy used at activation
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .arg("-d")
+        .arg("--collision")
+        .arg("prefix")
+        .assert();
+    assert
+        .success()
+        .stdout(predicate::str::contains("export BACKEND_PORT=3000\n"));
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("-d")
+        .assert();
+    assert.success().stdout(
+        predicate::str::contains("unset BACKEND_PORT\n")
+            .and(predicate::str::contains("unset PORT\n")),
+    );
+
+    Ok(())
+}
+
+#[test]
+fn inherit_from_ancestor() -> Result<(), CargoError> {
+    let root = create_project(
+        "inherit_from_ancestor",
+        &[
+            (
+                "activate.toml",
+                "[dev.env]\nAWS_REGION = \"eu-west-1\"\nLOG = \"debug\"\n",
+            ),
+            (
+                "services/api/activate.toml",
+                "inherit = true\n[dev.env]\nLOG = \"info\"\nPORT = \"3000\"\n",
+            ),
+        ],
+    );
+
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(root.join("services/api"))
+        .arg("dev")
+        .assert();
+    assert.success().stdout(predicate::eq(
+        r#"export AWS_REGION=eu-west-1
+export LOG=info
+export PORT=3000
+"#,
+    ));
+
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .arg("-d")
+        .arg("--collision")
+        .arg("child-wins")
+        .assert();
+    assert.success();
+    assert_eq!(
+        fs::read_to_string(root.join(".activate/active/.env")).unwrap(),
+        r#"# Generated - managed by `activate`.
+
+AWS_REGION="eu-west-1"
+LOG="info"
+PORT="3000"
+"#
+    );
+
+    Ok(())
+}
+
+#[test]
+fn descendants_cache() -> Result<(), CargoError> {
+    let root = create_project(
+        "descendants_cache",
+        &[
+            ("activate.toml", "[dev.env]\nROOT = \"root\"\n"),
+            ("a/activate.toml", "[dev.env]\nA = \"a\"\n"),
+            ("a/b/empty", ""),
+            ("a/.ignore", ""),
+        ],
+    );
+
+    for _ in 0..2 {
+        let assert = assert_cmd::Command::cargo_bin("activate")?
+            .current_dir(&root)
+            .arg("dev")
+            .arg("-d")
+            .assert();
+        assert
+            .success()
+            .stdout(predicate::str::contains("export A=a\nexport ROOT=root\n"));
+    }
+    assert!(root.join(".activate/.state/descendants.json").exists());
+
+    // a new `activate.toml` file invalidates the cache
+    fs::write(root.join("a/b/activate.toml"), "[dev.env]\nB = \"b\"\n").unwrap();
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .arg("-d")
+        .assert();
+    assert.success().stdout(predicate::str::contains(
+        "export A=a\nexport B=b\nexport ROOT=root\n",
+    ));
+
+    // so does a changed `.ignore` file
+    fs::write(root.join("a/.ignore"), "b/\n").unwrap();
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .arg("-d")
+        .assert();
+    assert
+        .success()
+        .stdout(predicate::str::contains("export A=a\nexport ROOT=root\n"));
+
+    // links created and removed by the activation leave the cache fresh
+    fs::write(root.join("a/dev.toml"), "dev").unwrap();
+    fs::write(
+        root.join("a/activate.toml"),
+        "[dev.env]\nA = \"a\"\n[dev.links]\n\"config/app.toml\" = \"dev.toml\"\n",
+    )
+    .unwrap();
+    for args in [&["dev", "-d"][..], &["-d"]] {
+        let assert = assert_cmd::Command::cargo_bin("activate")?
+            .current_dir(&root)
+            .args(args)
+            .assert();
+        assert.success();
+    }
+    // only a walk finds `c`, as its directory is added without changing the modified time of the root
+    let root_modified = fs::metadata(&root).unwrap().modified().unwrap();
+    fs::create_dir(root.join("c")).unwrap();
+    fs::write(root.join("c/activate.toml"), "[dev.env]\nC = \"c\"\n").unwrap();
+    fs::File::open(&root).unwrap().set_modified(root_modified).unwrap();
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .arg("-d")
+        .assert();
+    assert.success().stdout(
+        predicate::str::contains("export A=a\nexport ROOT=root\n")
+            .and(predicate::str::contains("C=c").not()),
+    );
+    assert!(root.join("a/config/app.toml").is_symlink());
+
+    Ok(())
+}
+
+#[test]
+fn library_workspace() -> activate::Result<()> {
+    let root = create_project(
+        "library_workspace",
+        &[
+            ("activate.toml", "[dev.env]\nHOST = \"localhost\"\n"),
+            (
+                "api/activate.toml",
+                "[dev.env]\nPORT = \"3000\"\n[dev.links]\n\"app.toml\" = \"config/dev.toml\"\n",
+            ),
+            ("api/config/dev.toml", "dev"),
+        ],
+    );
+
+    let workspace = Workspace::discover(&root, true)?;
+    assert_eq!(workspace.projects().len(), 2);
+    let plan = workspace.resolve(Some("dev"))?;
+    let (api, environment) = &plan.projects[1];
+    assert_eq!(api.dir(), root.join("api"));
+    let environment = environment.as_ref().unwrap();
+    assert_eq!(environment.env.get("PORT").unwrap(), "3000");
+    assert_eq!(
+        environment.links.get("app.toml").unwrap().source,
+        "config/dev.toml"
+    );
+    assert!(api.state()?.env.is_empty());
+
+    let envs = plan.apply(&ActivateOptions::default())?;
+    let root_env = envs
+        .iter()
+        .find(|env| env.activate_toml_file_directory == root)
+        .unwrap();
+    assert_eq!(root_env.new_env.get("HOST").unwrap(), "localhost");
+    assert_eq!(root_env.new_env.get("PORT").unwrap(), "3000");
+    let state = workspace.projects()[1].state()?;
+    assert_eq!(state.env.get("PORT").unwrap(), "3000");
+    assert_eq!(state.links.get("app.toml").unwrap(), "config/dev.toml");
+    assert_eq!(
+        fs::read_to_string(root.join("api/app.toml")).unwrap(),
+        "dev"
+    );
+
+    let envs = workspace.deactivate(&ActivateOptions::default())?;
+    let root_env = envs
+        .iter()
+        .find(|env| env.activate_toml_file_directory == root)
+        .unwrap();
+    assert!(root_env.new_env.is_empty());
+    assert_eq!(root_env.old_env.len(), 2);
+    assert!(!root.join("api/app.toml").exists());
+
+    Ok(())
+}
+
+#[test]
+fn typed_errors() -> Result<(), CargoError> {
+    let root = create_project(
+        "typed_errors",
+        &[
+            (
+                "activate.toml",
+                "[dev.env]\nHOST = \"localhost\"\n[dev.links]\n\"app.toml\" = \"missing.toml\"\n",
+            ),
+            ("broken/activate.toml", "[dev.env]\nHOST = \n"),
+        ],
+    );
+
+    let err = Workspace::discover(root.join("broken"), false).unwrap_err();
+    match &err {
+        Error::Parse { file, line, .. } => {
+            assert_eq!(file, &root.join("broken/activate.toml"));
+            assert_eq!(*line, 2);
+        }
+        err => panic!("Expected a parse error, got {err:?}"),
+    }
+    assert_eq!(err.exit_code(), 3);
+
+    let workspace = Workspace::discover(&root, false).unwrap();
+    let err = workspace.resolve(Some("prod")).unwrap_err();
+    assert!(matches!(err, Error::UnknownEnvironment { .. }));
+    let err = workspace
+        .activate("dev", &ActivateOptions::default())
+        .unwrap_err();
+    match &err {
+        Error::MissingSource { link, .. } => assert_eq!(link, "app.toml"),
+        err => panic!("Expected a missing source error, got {err:?}"),
+    }
+
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(root.join("broken"))
+        .arg("dev")
+        .assert();
+    assert
+        .code(3)
+        .stderr(predicate::str::contains("at line 2, column 8"));
+
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .assert();
+    assert.code(5).stderr(predicate::str::contains(
+        "The source `missing.toml` of link `app.toml`",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn load_active_env() -> Result<(), CargoError> {
+    let root = create_project(
+        "load_active_env",
+        &[
+            (
+                "activate.toml",
+                "[dev.env]\nPORT = \"3000\"\nHOST = \"localhost\"\n",
+            ),
+            ("src/empty", ""),
+        ],
+    );
+    assert!(matches!(
+        activate::load_from(root.join("src")),
+        Err(Error::NoActiveEnvironment(_))
+    ));
+
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .assert();
+    assert.success();
+
+    let env = activate::load_from(root.join("src")).unwrap();
+    assert_eq!(env.activate_file(), root.join("activate.toml"));
+    assert_eq!(env.get::<u16>("PORT").unwrap(), 3000);
+    assert_eq!(env.get_str("HOST"), Some("localhost"));
+    let err = env.get::<u16>("HOST").unwrap_err();
+    assert!(err.to_string().contains("activate.toml"));
+    // the value may be secret
+    assert!(!err.to_string().contains("localhost"));
+    assert!(matches!(
+        env.get::<u16>("MISSING"),
+        Err(Error::MissingVariable { .. })
+    ));
+
+    let directives = env.cargo_directives(Some(&["PORT"])).unwrap();
+    assert!(directives.contains(&format!(
+        "cargo:rerun-if-changed={}",
+        root.join("activate.toml").display()
+    )));
+    assert!(directives.contains(&"cargo:rustc-env=PORT=3000".to_string()));
+    assert!(!directives.iter().any(|d| d.contains("HOST")));
+    let directives = env.cargo_directives(None).unwrap();
+    assert!(directives.contains(&"cargo:rustc-env=HOST=localhost".to_string()));
+
+    Ok(())
+}
+
+#[test]
+fn secret_variables() -> Result<(), CargoError> {
+    let root = create_project(
+        "secret_variables",
+        &[
+            (
+                "activate.toml",
+                "[dev.env]\nHOST = \"localhost\"\nTOKEN = { value = \"abc123\", secret = true }\n",
+            ),
+            (
+                "api/activate.toml",
+                "[dev.env]\nAPI_KEY = { value = \"xyz789\", secret = true }\n",
+            ),
+        ],
+    );
+
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .arg("-d")
+        .assert();
+    assert
+        .success()
+        .stdout(predicate::str::contains("export TOKEN=abc123"));
+
+    let active_dir = root.join(".activate/active");
+    let env_file = fs::read_to_string(active_dir.join(".env")).unwrap();
+    assert!(env_file.contains("TOKEN=\"abc123\""));
+    assert!(env_file.contains("API_KEY=\"xyz789\""));
+    let configmap = fs::read_to_string(active_dir.join("configmap")).unwrap();
+    assert!(configmap.contains("HOST"));
+    assert!(!configmap.contains("abc123"));
+    assert!(!configmap.contains("xyz789"));
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        for file in [".env", "env.json"] {
+            let mode = fs::metadata(active_dir.join(file))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600, "{file}");
+        }
+        let mode = fs::metadata(root.join(".activate/.state/env.json"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    let workspace = Workspace::discover(&root, true).unwrap();
+    let envs = workspace
+        .activate("dev", &ActivateOptions::default())
+        .unwrap();
+    let root_env = envs
+        .iter()
+        .find(|env| env.activate_toml_file_directory == root)
+        .unwrap();
+    assert_eq!(root_env.display_value("TOKEN"), Some(activate::SECRET_MASK));
+    assert_eq!(
+        root_env.display_value("API_KEY"),
+        Some(activate::SECRET_MASK)
+    );
+    assert_eq!(root_env.display_value("HOST"), Some("localhost"));
+
+    Ok(())
+}
+
+#[test]
+fn kubernetes_secret() -> Result<(), CargoError> {
+    let root = create_project(
+        "kubernetes_secret",
+        &[(
+            "activate.toml",
+            r#"[prod.env]
+HOST = "example.com"
+DB_PASSWORD = "hunter2"
+TOKEN = { value = "abc123", secret = true }
+
+[prod.k8s]
+secret_keys = ["DB_PASSWORD"]
+
+[prod.k8s.secret]
+name = "api-secrets"
+namespace = "api"
+labels = { app = "api" }
+"#,
+        )],
+    );
+
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("prod")
+        .assert();
+    assert.success();
+
+    let active_dir = root.join(".activate/active");
+    let configmap = fs::read_to_string(active_dir.join("configmap")).unwrap();
+    assert!(configmap.contains("HOST"));
+    assert!(!configmap.contains("DB_PASSWORD"));
+    assert!(!configmap.contains("TOKEN"));
+    let secret = fs::read_to_string(active_dir.join("secret")).unwrap();
+    let secret: serde_yaml::Value = serde_yaml::from_str(&secret).unwrap();
+    assert_eq!(secret["kind"], "Secret");
+    assert_eq!(secret["metadata"]["name"], "api-secrets");
+    assert_eq!(secret["metadata"]["namespace"], "api");
+    assert_eq!(secret["metadata"]["labels"]["app"], "api");
+    assert_eq!(secret["data"]["DB_PASSWORD"], "aHVudGVyMg==");
+    assert_eq!(secret["data"]["TOKEN"], "YWJjMTIz");
+    assert!(secret["data"].get("HOST").is_none());
+
+    // the `secret_keys` of a descendant also apply to its ancestors, under the prefixed key
+    let root = create_project(
+        "kubernetes_secret_descendants",
+        &[
+            ("activate.toml", "[prod.env]\nHOST = \"example.com\"\n"),
+            (
+                "api/activate.toml",
+                "[prod.env]\nPORT = \"3000\"\nDB_PASSWORD = \"hunter2\"\n\n[prod.k8s]\nsecret_keys = [\"DB_PASSWORD\"]\n",
+            ),
+        ],
+    );
+    for (collision, key) in [("error", "DB_PASSWORD"), ("prefix", "API_DB_PASSWORD")] {
+        let assert = assert_cmd::Command::cargo_bin("activate")?
+            .current_dir(&root)
+            .args(["prod", "-d", "--collision", collision])
+            .assert();
+        assert.success();
+
+        let configmap = fs::read_to_string(root.join(".activate/active/configmap")).unwrap();
+        assert!(configmap.contains("HOST"));
+        assert!(configmap.contains("PORT"));
+        assert!(!configmap.contains("DB_PASSWORD"));
+        assert!(!configmap.contains("hunter2"));
+        let secret = fs::read_to_string(root.join(".activate/active/secret")).unwrap();
+        let secret: serde_yaml::Value = serde_yaml::from_str(&secret).unwrap();
+        assert_eq!(secret["data"][key], "aHVudGVyMg==");
+
+        let configmap = fs::read_to_string(root.join("api/.activate/active/configmap")).unwrap();
+        assert!(configmap.contains("PORT"));
+        assert!(!configmap.contains("DB_PASSWORD"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn configmap_yaml() -> Result<(), CargoError> {
+    let root = create_project(
+        "configmap_yaml",
+        &[(
+            "activate.toml",
+            r#"[dev.env]
+PORT = "3000"
+QUOTED = "say \"hi\"\\n"
+MULTILINE = "line 1\nline 2"
+
+[dev.k8s.configmap]
+name = "api-config"
+namespace = "api"
+labels = { app = "api" }
+annotations = { "example.com/owner" = "team: api" }
+immutable = true
+"#,
+        )],
+    );
+
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .assert();
+    assert.success();
+
+    let configmap_file = root.join(".activate/active/configmap");
+    let configmap = fs::read_to_string(&configmap_file).unwrap();
+    let configmap: serde_yaml::Value = serde_yaml::from_str(&configmap).unwrap();
+    assert_eq!(configmap["kind"], "ConfigMap");
+    assert_eq!(configmap["immutable"], true);
+    assert_eq!(configmap["metadata"]["name"], "api-config");
+    assert_eq!(configmap["metadata"]["namespace"], "api");
+    assert_eq!(configmap["metadata"]["labels"]["app"], "api");
+    assert_eq!(
+        configmap["metadata"]["annotations"]["example.com/owner"],
+        "team: api"
+    );
+    assert_eq!(configmap["data"]["PORT"], "3000");
+    assert_eq!(configmap["data"]["QUOTED"], "say \"hi\"\\n");
+    assert_eq!(configmap["data"]["MULTILINE"], "line 1\nline 2");
+
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .args(["dev", "--configmap-name", "override"])
+        .assert();
+    assert.success();
+    let configmap = fs::read_to_string(&configmap_file).unwrap();
+    let configmap: serde_yaml::Value = serde_yaml::from_str(&configmap).unwrap();
+    assert_eq!(configmap["metadata"]["name"], "override");
+
+    Ok(())
+}
+
+#[test]
+fn output_formats() -> Result<(), CargoError> {
+    let root = create_project(
+        "output_formats",
+        &[(
+            "activate.toml",
+            r#"[outputs]
+json = false
+systemd = true
+properties = "config/app.properties"
+tfvars = true
+helm = true
+direnv = ".envrc"
+
+[dev.env]
+HOST = "localhost"
+GREETING = "it's \"${name}\" = ünïcode"
+"#,
+        )],
+    );
+    fs::create_dir_all(root.join(".activate/active")).unwrap();
+    fs::write(root.join(".activate/active/env.json"), "{}").unwrap();
+
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .assert();
+    assert.success();
+
+    let active_dir = root.join(".activate/active");
+    assert!(active_dir.join(".env").exists());
+    assert!(active_dir.join("configmap").exists());
+    assert!(!active_dir.join("env.json").exists());
+    let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+    assert!(
+        read(active_dir.join("systemd.env")).contains(r#"GREETING="it's \"${name}\" = ünïcode""#)
+    );
+    assert!(read(root.join("config/app.properties"))
+        .contains(r#"GREETING=it's "${name}" = \u00fcn\u00efcode"#));
+    assert!(
+        read(active_dir.join("env.tfvars")).contains(r#"GREETING = "it's \"$${name}\" = ünïcode""#)
+    );
+    let values: serde_yaml::Value =
+        serde_yaml::from_str(&read(active_dir.join("values.yaml"))).unwrap();
+    assert_eq!(values["env"]["GREETING"], "it's \"${name}\" = ünïcode");
+    assert!(read(root.join(".envrc")).contains(r#"export GREETING='it'\''s "${name}" = ünïcode'"#));
+
+    // paths are confined to the `activate.toml` directory, and files not written by `activate` are not overwritten
+    let toml = read(root.join("activate.toml"));
+    fs::write(root.join("README.txt"), "readme").unwrap();
+    for (output, code, message) in [
+        ("dotenv = \"../output_formats.env\"", 5, "is outside of the `activate.toml` directory."),
+        ("properties = \"README.txt\"", 5, "README.txt` of output `properties` in `./activate.toml` already exists."),
+    ] {
+        fs::write(root.join("activate.toml"), format!("[outputs]\n{output}\n\n[dev.env]\nHOST = \"localhost\"\n")).unwrap();
+        let assert = assert_cmd::Command::cargo_bin("activate")?
+            .current_dir(&root)
+            .arg("dev")
+            .assert();
+        assert.code(code).stderr(predicate::str::contains(message));
+    }
+    assert!(!root.join("../output_formats.env").exists());
+    assert_eq!(read(root.join("README.txt")), "readme");
+
+    // outputs written to a path that is not configured anymore are removed
+    fs::write(root.join("activate.toml"), toml.replace("direnv = \".envrc\"\n", "")).unwrap();
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .assert();
+    assert.success();
+    assert!(!root.join(".envrc").exists());
+    assert!(root.join("config/app.properties").exists());
+
+    Ok(())
+}
+
+#[test]
+fn output_templates() -> Result<(), CargoError> {
+    let root = create_project(
+        "output_templates",
+        &[
+            (
+                "activate.toml",
+                r#"[outputs.templates]
+"templates/config.js" = "public/config.js"
+"templates/nginx.conf" = "nginx/env.conf"
+
+[dev.env]
+API_URL = "http://localhost:3000"
+NAME = "o'neil"
+"#,
+            ),
+            (
+                "templates/config.js",
+                "window.config = {\n{% for key, value in env %}\n  {{ key | lower | json }}: {{ value | json }},\n{% endfor %}\n};\n",
+            ),
+            (
+                "templates/nginx.conf",
+                "set $api {{ API_URL }};\n# {{ NAME | upper | shell-quote }}\n",
+            ),
+        ],
+    );
+
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .assert();
+    assert.success();
+
+    assert_eq!(
+        fs::read_to_string(root.join("public/config.js")).unwrap(),
+        "window.config = {\n  \"api_url\": \"http://localhost:3000\",\n  \"name\": \"o'neil\",\n};\n"
+    );
+    assert_eq!(
+        fs::read_to_string(root.join("nginx/env.conf")).unwrap(),
+        "set $api http://localhost:3000;\n# 'O'\\''NEIL'\n"
+    );
+
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .assert();
+    assert.success();
+    assert!(!root.join("public/config.js").exists());
+    assert!(!root.join("nginx/env.conf").exists());
+
+    fs::write(root.join("templates/nginx.conf"), "ok\n{{ MISSING }}\n").unwrap();
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .assert();
+    assert.failure().code(3).stderr(predicate::str::contains(
+        "nginx.conf` at line 2, column 1. `MISSING` is not defined.",
+    ));
+    // templates are rendered before anything is activated
+    assert!(!root.join(".activate/.state/activation.json").exists());
+    assert!(!root.join("public/config.js").exists());
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .assert();
+    assert.success().stdout("");
+
+    // files not rendered by `activate` are neither overwritten nor removed
+    fs::write(root.join("notes.txt"), "notes").unwrap();
+    fs::write(
+        root.join("activate.toml"),
+        "[outputs.templates]\n\"templates/config.js\" = \"notes.txt\"\n\n[dev.env]\nNAME = \"dev\"\n",
+    )
+    .unwrap();
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .assert();
+    assert.failure().code(5).stderr(predicate::str::contains(
+        "of template `notes.txt` in `./activate.toml` already exists.",
+    ));
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .assert();
+    assert.success();
+    assert_eq!(fs::read_to_string(root.join("notes.txt")).unwrap(), "notes");
+
+    // outputs are confined to the `activate.toml` directory
+    for target in ["/tmp/activate-output.js", "../output.js"] {
+        fs::write(
+            root.join("activate.toml"),
+            format!("[outputs.templates]\n\"templates/config.js\" = \"{target}\"\n\n[dev.env]\nNAME = \"dev\"\n"),
+        )
+        .unwrap();
+        let assert = assert_cmd::Command::cargo_bin("activate")?
+            .current_dir(&root)
+            .arg("dev")
+            .assert();
+        assert
+            .failure()
+            .code(5)
+            .stderr(predicate::str::contains("is outside of the `activate.toml` directory"));
+        assert!(!root.join(".activate/.state/env.json").exists());
+    }
+    assert!(!Path::new("/tmp/activate-output.js").exists());
+
+    Ok(())
+}
+
+#[test]
+fn dotenv_flavors() -> Result<(), CargoError> {
+    let pem = "-----BEGIN KEY-----\nMIIB\\x/+=\n-----END KEY-----";
+    let values = [
+        ("PEM", pem),
+        ("HASH", "a # not a comment"),
+        ("QUOTES", r#"say "hi" it's"#),
+        ("SPACES", "  padded  "),
+        ("DOLLAR", "$HOME ${USER}"),
+        ("BACKSLASH", r"C:\path\n"),
+    ];
+    let env_table = values
+        .iter()
+        .map(|(key, value)| format!("{key} = {}\n", serde_json::to_string(value).unwrap()))
+        .collect::<String>();
+    let activate = |flavor: &str| -> Result<PathBuf, CargoError> {
+        let root = create_project(
+            &format!("dotenv_flavors_{flavor}"),
+            &[(
+                "activate.toml",
+                &format!("[outputs]\ndotenv_flavor = \"{flavor}\"\n\n[dev.env]\n{env_table}"),
+            )],
+        );
+        let assert = assert_cmd::Command::cargo_bin("activate")?
+            .current_dir(&root)
+            .arg("dev")
+            .assert();
+        assert.success();
+        Ok(root.join(".activate/active/.env"))
+    };
+    let expected = values
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect::<HashMap<_, _>>();
+
+    let env_file = activate("dotenv")?;
+    // unlike `from_path`, does not set the env of the test process
+    #[allow(deprecated)]
+    let parsed = dotenv::from_path_iter(&env_file)
+        .unwrap()
+        .collect::<Result<HashMap<_, _>, _>>()
+        .unwrap();
+    assert_eq!(parsed, expected);
+    let parsed = dotenvy::from_path_iter(&env_file)
+        .unwrap()
+        .collect::<Result<HashMap<_, _>, _>>()
+        .unwrap();
+    assert_eq!(parsed, expected);
+
+    let env_file = activate("docker")?;
+    assert!(fs::read_to_string(&env_file)
+        .unwrap()
+        .contains("PEM=\"-----BEGIN KEY-----\nMIIB"));
+    let parsed = dotenvy::from_path_iter(&env_file)
+        .unwrap()
+        .collect::<Result<HashMap<_, _>, _>>()
+        .unwrap();
+    assert_eq!(parsed, expected);
+
+    let root = create_project(
+        "dotenv_flavors_plain",
+        &[(
+            "activate.toml",
+            "[outputs]\ndotenv_flavor = \"plain\"\n\n[dev.env]\nHASH = \"a # b\"\nPEM = \"a\\nb\"\n",
+        )],
+    );
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .assert();
+    assert
+        .failure()
+        .code(2)
+        .stderr(predicate::str::contains("The value `a\nb` of `PEM`"));
+    // the .env file is rendered before anything is activated
+    assert!(!root.join(".activate/.state/env.json").exists());
+    let pem_toml = fs::read_to_string(root.join("activate.toml")).unwrap();
+    fs::write(
+        root.join("activate.toml"),
+        "[outputs]\ndotenv_flavor = \"plain\"\n\n[dev.env]\nHASH = \"a # b\"\n",
+    )
+    .unwrap();
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .assert();
+    assert.success();
+    assert!(fs::read_to_string(root.join(".activate/active/.env"))
+        .unwrap()
+        .ends_with("\nHASH=a # b\n"));
+
+    fs::write(root.join("activate.toml"), pem_toml).unwrap();
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .assert();
+    assert.failure().code(2).stdout("");
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .assert();
+    assert.success().stdout("unset HASH\n");
+
+    Ok(())
+}
+
+#[test]
+fn environment_templates() -> Result<(), CargoError> {
+    let root = create_project(
+        "environment_templates",
+        &[
+            (
+                "activate.toml",
+                r#"[dev.env]
+DB_HOST = "localhost"
+
+[dev.templates]
+"config/app.yaml" = "config/app.yaml.tmpl"
+
+[prod.env]
+DB_HOST = "db.internal"
+
+[prod.templates]
+"config/app.yaml" = "config/app.yaml.tmpl"
+"#,
+            ),
+            (
+                "config/app.yaml.tmpl",
+                "database:\n  host: {{ DB_HOST | json }}\n",
+            ),
+        ],
+    );
+    let target = root.join("config/app.yaml");
+
+    for (env_name, host) in [("dev", "localhost"), ("prod", "db.internal")] {
+        let assert = assert_cmd::Command::cargo_bin("activate")?
+            .current_dir(&root)
+            .arg(env_name)
+            .assert();
+        assert.success();
+        assert_eq!(
+            fs::read_to_string(&target).unwrap(),
+            format!("database:\n  host: \"{host}\"\n")
+        );
+    }
+    let workspace = Workspace::discover(&root, false).unwrap();
+    let state = workspace.state().unwrap();
+    assert_eq!(
+        state[0].1.templates.get("config/app.yaml").unwrap(),
+        "config/app.yaml.tmpl"
+    );
+
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .assert();
+    assert.success();
+    assert!(!target.exists());
+    assert!(root.join("config/app.yaml.tmpl").exists());
+
+    fs::write(&target, "not managed").unwrap();
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .assert();
+    assert
+        .failure()
+        .code(5)
+        .stderr(predicate::str::contains("already exists"));
+    fs::remove_file(&target).unwrap();
+
+    // a modified rendered file is kept, unless forced
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .assert();
+    assert.success();
+    fs::write(&target, "database:\n  host: edited\n").unwrap();
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .assert();
+    assert
+        .success()
+        .stderr(predicate::str::contains("was modified locally"));
+    assert_eq!(
+        fs::read_to_string(&target).unwrap(),
+        "database:\n  host: edited\n"
+    );
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("--force")
+        .assert();
+    assert.success();
+    assert!(!target.exists());
+
+    // templates are confined to the `activate.toml` directory, unless allowed
+    let outside = env::temp_dir().join("activate-tests/environment_templates_outside.yaml");
+    let _ = fs::remove_file(&outside);
+    for target in [
+        outside.display().to_string(),
+        "../environment_templates_outside.yaml".to_string(),
+    ] {
+        fs::write(
+            root.join("activate.toml"),
+            format!("[dev.env]\nDB_HOST = \"localhost\"\n\n[dev.templates]\n\"{target}\" = \"config/app.yaml.tmpl\"\n"),
+        )
+        .unwrap();
+        let assert = assert_cmd::Command::cargo_bin("activate")?
+            .current_dir(&root)
+            .arg("dev")
+            .assert();
+        assert
+            .failure()
+            .code(5)
+            .stderr(predicate::str::contains("is outside of the `activate.toml` directory"));
+        assert!(!outside.exists());
+    }
+    fs::write(
+        root.join("activate.toml"),
+        "[dev.env]\nDB_HOST = \"localhost\"\n\n[dev.templates]\n\"../environment_templates_outside.yaml\" = { template = \"config/app.yaml.tmpl\", allow_outside_root = true }\n",
+    )
+    .unwrap();
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .arg("dev")
+        .assert();
+    assert.success();
+    assert!(outside.exists());
+    let assert = assert_cmd::Command::cargo_bin("activate")?
+        .current_dir(&root)
+        .assert();
+    assert.success();
+    assert!(!outside.exists());
+
+    Ok(())
+}
+
+#[test]
+fn link_modes() -> Result<(), CargoError> {
+    let root = create_project(
+        "link_modes",
+        &[
+            (
+                "activate.toml",
+                r#"[dev.links]
+"app.toml" = { source = "config/dev.toml", mode = "copy" }
+"assets" = { source = "config/assets", mode = "copy" }
+"hard.toml" = { source = "config/dev.toml", mode = "hardlink" }
+"sym.toml" = { source = "config/dev.toml", mode = "symlink" }
+"#,
+            ),
+            ("config/dev.toml", "dev"),
+            ("config/assets/logo.svg", "<svg/>"),
+        ],
+    );
+    let activate = |args: &[&str]| -> Result<assert_cmd::assert::Assert, CargoError> {
+        Ok(assert_cmd::Command::cargo_bin("activate")?
+            .current_dir(&root)
+            .args(args)
+            .assert())
+    };
+
+    activate(&["dev"])?.success();
+    for target in ["app.toml", "hard.toml", "sym.toml"] {
+        assert_eq!(fs::read_to_string(root.join(target)).unwrap(), "dev");
+    }
+    assert!(!root.join("app.toml").is_symlink());
+    assert!(!root.join("hard.toml").is_symlink());
+    assert!(root.join("sym.toml").is_symlink());
+    assert_eq!(
+        fs::read_to_string(root.join("assets/logo.svg")).unwrap(),
+        "<svg/>"
+    );
+    let links_state = fs::read_to_string(root.join(".activate/.state/links.toml")).unwrap();
+    assert!(links_state.contains("checksum"));
+
+    activate(&[])?.success();
+    for target in ["app.toml", "assets", "hard.toml", "sym.toml"] {
+        assert!(!root.join(target).exists(), "{target}");
+    }
+    assert_eq!(
+        fs::read_to_string(root.join("config/dev.toml")).unwrap(),
+        "dev"
+    );
+
+    activate(&["dev"])?.success();
+    fs::write(root.join("app.toml"), "edited").unwrap();
+    fs::write(root.join("assets/logo.svg"), "<svg></svg>").unwrap();
+    activate(&[])?
+        .success()
+        .stderr(predicate::str::contains("app.toml` was modified locally"))
+        .stderr(predicate::str::contains("assets` was modified locally"));
+    assert_eq!(fs::read_to_string(root.join("app.toml")).unwrap(), "edited");
+    assert!(root.join("assets/logo.svg").exists());
+    assert!(!root.join("hard.toml").exists());
+
+    // modified copies stay in the links state, so they are not taken for files of the user
+    let links_state = fs::read_to_string(root.join(".activate/.state/links.toml")).unwrap();
+    assert!(links_state.contains("app.toml"));
+    assert!(links_state.contains("assets"));
+    activate(&["dev"])?
+        .failure()
+        .code(5)
+        .stderr(predicate::str::contains("already exists"));
+    assert!(!root.join(".activate/.state/activation.json").exists());
+    assert!(!root.join(".activate/.state/env.json").exists());
+    activate(&["--force"])?.success();
+    assert!(!root.join("app.toml").exists());
+    assert!(!root.join("assets").exists());
+    assert!(!root.join(".activate/.state/links.toml").exists());
+    activate(&["dev"])?.success();
+    assert_eq!(fs::read_to_string(root.join("app.toml")).unwrap(), "dev");
+
+    Ok(())
+}
+
+#[test]
+fn glob_and_mirror_links() -> Result<(), CargoError> {
+    let root = create_project(
+        "glob_and_mirror_links",
+        &[
+            (
+                "activate.toml",
+                r#"[dev.links]
+"config/*.yaml" = ".activate/dev/config/*.yaml"
+"bin" = { source = ".activate/dev/bin", mirror = true }
+"#,
+            ),
+            (".activate/dev/config/app.yaml", "app"),
+            (".activate/dev/config/db.yaml", "db"),
+            (".activate/dev/config/notes.txt", "notes"),
+            (".activate/dev/bin/run", "run"),
+            (".activate/dev/bin/tools/lint", "lint"),
+            ("config/keep.txt", "keep"),
+            ("bin/local", "local"),
+            ("bin/tools/.gitkeep", ""),
+        ],
+    );
+    let activate = |args: &[&str]| -> Result<assert_cmd::assert::Assert, CargoError> {
+        Ok(assert_cmd::Command::cargo_bin("activate")?
+            .current_dir(&root)
+            .args(args)
+            .assert())
+    };
+
+    activate(&["dev"])?.success();
+    assert_eq!(
+        fs::read_to_string(root.join("config/app.yaml")).unwrap(),
+        "app"
+    );
+    assert_eq!(
+        fs::read_to_string(root.join("config/db.yaml")).unwrap(),
+        "db"
+    );
+    assert!(!root.join("config/notes.txt").exists());
+    assert!(root.join("bin/run").is_symlink());
+    assert_eq!(
+        fs::read_to_string(root.join("bin/tools/lint")).unwrap(),
+        "lint"
+    );
+    let links_state = fs::read_to_string(root.join(".activate/.state/links.toml")).unwrap();
+    for target in [
+        "config/app.yaml",
+        "config/db.yaml",
+        "bin/run",
+        "bin/tools/lint",
+    ] {
+        assert!(links_state.contains(&format!("\"{target}\"")), "{target}");
+    }
+
+    activate(&[])?.success();
+    for target in [
+        "config/app.yaml",
+        "config/db.yaml",
+        "bin/run",
+        "bin/tools/lint",
+    ] {
+        assert!(!root.join(target).exists(), "{target}");
+    }
+    assert!(root.join("config/keep.txt").exists());
+    assert!(root.join("bin/local").exists());
+    assert!(root.join("bin/tools/.gitkeep").exists());
+
+    Ok(())
+}
+
+#[test]
+fn link_parent_directories() -> Result<(), CargoError> {
+    let root = create_project(
+        "link_parent_directories",
+        &[
+            (
+                "activate.toml",
+                r#"[dev.links]
+"build/conf/app.toml" = "config/dev.toml"
+"build/conf/db.toml" = { source = "config/dev.toml", mode = "copy" }
+"config/local/dev.toml" = "config/dev.toml"
+"#,
+            ),
+            ("config/dev.toml", "dev"),
+        ],
+    );
+    let activate = |args: &[&str]| -> Result<assert_cmd::assert::Assert, CargoError> {
+        Ok(assert_cmd::Command::cargo_bin("activate")?
+            .current_dir(&root)
+            .args(args)
+            .assert())
+    };
+
+    activate(&["dev"])?.success();
+    assert_eq!(
+        fs::read_to_string(root.join("build/conf/app.toml")).unwrap(),
+        "dev"
+    );
+    assert_eq!(
+        fs::read_to_string(root.join("build/conf/db.toml")).unwrap(),
+        "dev"
+    );
+    assert_eq!(
+        fs::read_to_string(root.join("config/local/dev.toml")).unwrap(),
+        "dev"
+    );
+
+    activate(&[])?.success();
+    assert!(!root.join("build").exists());
+    assert!(!root.join("config/local").exists());
+    assert!(root.join("config/dev.toml").exists());
+
+    activate(&["dev"])?.success();
+    fs::write(root.join("build/notes.txt"), "notes").unwrap();
+    activate(&[])?.success();
+    assert!(!root.join("build/conf").exists());
+    assert!(root.join("build/notes.txt").exists());
+
+    Ok(())
+}
+
+#[test]
+fn optional_and_conditional_links() -> Result<(), CargoError> {
+    let root = create_project(
+        "optional_and_conditional_links",
+        &[
+            (
+                "activate.toml",
+                r#"[dev.links]
+"data" = { source = "downloads/data", optional = true }
+"samples/*.csv" = { source = "downloads/*.csv", optional = true }
+"app.toml" = { source = "config/dev.toml", when = { exists = "config/dev.toml" } }
+"gpu.toml" = { source = "config/dev.toml", when = { exists = "/dev/activate-gpu" } }
+"ci.toml" = { source = "config/dev.toml", when = { env = "ACTIVATE_TEST_CI" } }
+"#,
+            ),
+            ("config/dev.toml", "dev"),
+        ],
+    );
+    let activate = |args: &[&str], ci: &str| -> Result<assert_cmd::assert::Assert, CargoError> {
+        Ok(assert_cmd::Command::cargo_bin("activate")?
+            .current_dir(&root)
+            .env("ACTIVATE_TEST_CI", ci)
+            .args(args)
+            .assert())
+    };
+
+    activate(&["dev"], "")?
+        .success()
+        .stderr(predicate::str::contains(
+            "The source `downloads/data` of the optional link `data` does not exist.",
+        ))
+        .stderr(predicate::str::contains(
+            "The source `downloads/*.csv` of the optional link `samples/*.csv` matched nothing.",
+        ));
+    assert!(!root.join("data").exists());
+    assert!(root.join("app.toml").is_symlink());
+    assert!(!root.join("gpu.toml").exists());
+    assert!(!root.join("ci.toml").exists());
+
+    activate(&["dev"], "true")?.success();
+    assert!(root.join("ci.toml").is_symlink());
+
+    fs::create_dir_all(root.join("downloads/data")).unwrap();
+    activate(&["dev"], "")?
+        .success()
+        .stderr(predicate::str::contains("does not exist").not());
+    assert!(root.join("data").is_symlink());
+    assert!(!root.join("ci.toml").exists());
+
+    activate(&[], "")?.success();
+    assert!(!root.join("data").exists());
+    assert!(!root.join("app.toml").exists());
+
+    Ok(())
+}
+
+#[test]
+fn links_replaced_atomically() -> Result<(), CargoError> {
+    let root = create_project(
+        "links_replaced_atomically",
+        &[
+            (
+                "activate.toml",
+                r#"[dev.links]
+"build/app.toml" = "config/dev.toml"
+"assets" = { source = "config/dev-assets", mode = "copy" }
+"data" = { source = "config/dev-assets", mode = "copy" }
+"dev.toml" = "config/dev.toml"
+
+[qa.links]
+"build/app.toml" = "config/qa.toml"
+"assets" = { source = "config/qa-assets", mode = "copy" }
+"data" = "config/qa-assets"
+
+[missing.links]
+"0first" = "config/missing.toml"
+"build/app.toml" = "config/qa.toml"
+
+[blocked.links]
+"0first" = "config/qa.toml"
+"build/app.toml" = "config/qa.toml"
+"#,
+            ),
+            ("config/dev.toml", "dev"),
+            ("config/qa.toml", "qa"),
+            ("config/dev-assets/logo.svg", "dev"),
+            ("config/qa-assets/logo.svg", "qa"),
+        ],
+    );
+    let activate = |args: &[&str]| -> Result<assert_cmd::assert::Assert, CargoError> {
+        Ok(assert_cmd::Command::cargo_bin("activate")?
+            .current_dir(&root)
+            .args(args)
+            .assert())
+    };
+
+    activate(&["dev"])?.success();
+    activate(&["qa"])?.success();
+    assert_eq!(
+        fs::read_to_string(root.join("build/app.toml")).unwrap(),
+        "qa"
+    );
+    assert_eq!(
+        fs::read_to_string(root.join("assets/logo.svg")).unwrap(),
+        "qa"
+    );
+    assert!(!root.join("dev.toml").exists());
+    assert!(!root.join("build/.app.toml.activate-tmp").exists());
+    assert!(!root.join(".assets.activate-tmp").exists());
+    // a copied directory is replaced by a symlink, and the other way around
+    assert!(root.join("data").is_symlink());
+    assert_eq!(fs::read_to_string(root.join("data/logo.svg")).unwrap(), "qa");
+    assert!(!root.join(".data.activate-tmp").exists());
+
+    activate(&["dev"])?.success();
+    assert_eq!(
+        fs::read_to_string(root.join("build/app.toml")).unwrap(),
+        "dev"
+    );
+    assert!(!root.join("data").is_symlink());
+    assert_eq!(
+        fs::read_to_string(root.join("data/logo.svg")).unwrap(),
+        "dev"
+    );
+    let links_state = fs::read_to_string(root.join(".activate/.state/links.toml")).unwrap();
+    assert!(links_state.contains("config/dev-assets"));
+    activate(&["qa"])?.success();
+    assert!(root.join("data").is_symlink());
+    activate(&["dev"])?.success();
+
+    // A missing source fails the switch before anything is deactivated.
+    activate(&["missing"])?
+        .code(5)
+        .stderr(predicate::str::contains("config/missing.toml"));
+    assert!(root.join(".activate/.state/activation.json").exists());
+    assert_eq!(fs::read_to_string(root.join("dev.toml")).unwrap(), "dev");
+    // A switch failing after that still records the links it kept for replacing.
+    fs::write(root.join("0first"), "mine").unwrap();
+    activate(&["blocked"])?.code(5);
+    let links_state = fs::read_to_string(root.join(".activate/.state/links.toml")).unwrap();
+    assert!(links_state.contains("build/app.toml"));
+    assert!(!links_state.contains("\"dev.toml\" ="));
+    fs::remove_file(root.join("0first")).unwrap();
+    activate(&["dev"])?.success();
+
+    // The directory created by the first environment is still removed.
+    activate(&[])?.success();
+    assert!(!root.join("build").exists());
+    assert!(!root.join("assets").exists());
+    assert!(!root.join("data").exists());
+
+    Ok(())
+}
+
+#[test]
+fn dangling_and_tampered_links() -> Result<(), CargoError> {
+    let root = create_project(
+        "dangling_and_tampered_links",
+        &[
+            (
+                "activate.toml",
+                r#"[dev.links]
+"data" = "downloads/data"
+"app.toml" = "config/dev.toml"
+"#,
+            ),
+            ("downloads/data/rows.csv", "1,2"),
+            ("config/dev.toml", "dev"),
+            ("config/other.toml", "other"),
+        ],
+    );
+    let activate = |args: &[&str]| -> Result<assert_cmd::assert::Assert, CargoError> {
+        Ok(assert_cmd::Command::cargo_bin("activate")?
+            .current_dir(&root)
+            .args(args)
+            .assert())
+    };
+
+    activate(&["dev"])?.success();
+    fs::remove_dir_all(root.join("downloads")).unwrap();
+    fs::remove_file(root.join("app.toml")).unwrap();
+    std::os::unix::fs::symlink("config/other.toml", root.join("app.toml")).unwrap();
+
+    activate(&[])?.success().stderr(predicate::str::contains(
+        "app.toml` points somewhere other than where it was created. Therefore it was not removed. Use `--force` to remove it.",
+    ));
+    assert!(fs::symlink_metadata(root.join("data")).is_err());
+    assert!(root.join("app.toml").is_symlink());
+    let links_state = fs::read_to_string(root.join(".activate/.state/links.toml")).unwrap();
+    assert!(links_state.contains("app.toml"));
+    assert!(!links_state.contains("data"));
+
+    activate(&["--force"])?.success();
+    assert!(fs::symlink_metadata(root.join("app.toml")).is_err());
+    assert!(!root.join(".activate/.state/links.toml").exists());
+    assert_eq!(
+        fs::read_to_string(root.join("config/other.toml")).unwrap(),
+        "other"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn doctor() -> Result<(), CargoError> {
+    let root = create_project(
+        "doctor",
+        &[
+            (
+                "activate.toml",
+                r#"[dev.env]
+HOST = "localhost"
+
+[dev.links]
+"app.toml" = "config/dev.toml"
+"data" = "config/data"
+"#,
+            ),
+            ("config/dev.toml", "dev"),
+            ("config/data/rows.csv", "1,2"),
+        ],
+    );
+    let activate = |args: &[&str]| -> Result<assert_cmd::assert::Assert, CargoError> {
+        Ok(assert_cmd::Command::cargo_bin("activate")?
+            .current_dir(&root)
+            .args(args)
+            .assert())
+    };
+    let state_dir = root.join(".activate/.state");
+
+    activate(&["doctor"])?
+        .success()
+        .stdout(predicate::str::contains("No inconsistencies found."));
+
+    activate(&["dev"])?.success();
+    let mut env_json = fs::read_to_string(state_dir.join("env.json")).unwrap();
+    env_json.push_str(r#"{"EXTRA":"1"}"#);
+    fs::write(state_dir.join("env.json"), env_json).unwrap();
+    fs::remove_file(state_dir.join("links.toml")).unwrap();
+
+    activate(&["doctor"])?
+        .code(1)
+        .stdout(predicate::str::contains(
+            "holds 2 env objects instead of one",
+        ))
+        .stdout(predicate::str::contains(
+            "The symlink `app.toml` to `config/dev.toml` is not in the links state",
+        ))
+        .stdout(predicate::str::contains(
+            "Fix: Add `data` to the links state.",
+        ));
+    activate(&["doctor", "--fix"])?
+        .success()
+        .stdout(predicate::str::contains(
+            "Fixed: Add `app.toml` to the links state.",
+        ));
+    activate(&["doctor"])?
+        .success()
+        .stdout(predicate::str::contains("No inconsistencies found."));
+
+    activate(&[])?
+        .success()
+        .stdout(predicate::str::contains("unset EXTRA"))
+        .stdout(predicate::str::contains("unset HOST"));
+    assert!(!root.join("app.toml").exists());
+    assert!(!root.join("data").exists());
+
+    activate(&["dev"])?.success();
+    fs::remove_file(root.join("data")).unwrap();
+    activate(&["doctor", "--fix"])?
+        .success()
+        .stdout(predicate::str::contains(
+            "The link `data` is in the links state, but does not exist.",
+        ));
+    let links_state = fs::read_to_string(state_dir.join("links.toml")).unwrap();
+    assert!(!links_state.contains("data"));
+
+    fs::write(state_dir.join("templates.toml"), "not toml").unwrap();
+    activate(&["doctor", "--fix"])?
+        .success()
+        .stdout(predicate::str::contains(
+            "templates.toml` could not be parsed.",
+        ));
+    assert!(!state_dir.join("templates.toml").exists());
+
+    activate(&[])?.success();
+    std::os::unix::fs::symlink("config/dev.toml", root.join("app.toml")).unwrap();
+    activate(&["doctor", "--fix"])?
+        .success()
+        .stdout(predicate::str::contains(
+            "The symlink `app.toml` to `config/dev.toml` was left behind",
+        ));
+    assert!(fs::symlink_metadata(root.join("app.toml")).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn links_outside_root() -> Result<(), CargoError> {
+    let root = create_project(
+        "links_outside_root",
+        &[
+            (
+                "app/activate.toml",
+                r#"[escape.links]
+"config/../../escaped.toml" = "config/dev.toml"
+
+[absolute.links]
+"app.toml" = "/etc/hostname"
+
+[shared.links]
+"app.toml" = { source = "../shared/dev.toml", allow_outside_root = true }
+"../shared/app.toml" = { source = "config/dev.toml", allow_outside_root = true }
+
+[symlinked.links]
+"esc/pwn" = "config/dev.toml"
+
+[symlinked_source.links]
+"app.toml" = "esc/dev.toml"
+
+[mirrored.links]
+"esc" = { source = "config", mirror = true }
+
+[globbed.links]
+"copies/*" = { source = "src/*", mode = "copy" }
+"#,
+            ),
+            ("app/config/dev.toml", "dev"),
+            ("app/src/local.toml", "local"),
+            ("shared/dev.toml", "shared"),
+        ],
+    );
+    let app = root.join("app");
+    let activate = |args: &[&str]| -> Result<assert_cmd::assert::Assert, CargoError> {
+        Ok(assert_cmd::Command::cargo_bin("activate")?
+            .current_dir(&app)
+            .args(args)
+            .assert())
+    };
+
+    activate(&["escape"])?.code(5).stderr(predicate::str::contains(
+        "The target `config/../../escaped.toml` is outside of the `activate.toml` directory. Set `allow_outside_root = true` to allow it.",
+    ));
+    assert!(!root.join("escaped.toml").exists());
+    activate(&["absolute"])?
+        .code(5)
+        .stderr(predicate::str::contains(
+            "The source `/etc/hostname` is outside of the `activate.toml` directory.",
+        ));
+    assert!(!app.join("app.toml").exists());
+
+    // symlinked directories can not lead outside either
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(root.join("shared"), app.join("esc")).unwrap();
+        activate(&["symlinked"])?
+            .code(5)
+            .stderr(predicate::str::contains(
+                "The target `esc/pwn` is outside of the `activate.toml` directory.",
+            ));
+        assert!(fs::symlink_metadata(root.join("shared/pwn")).is_err());
+        activate(&["symlinked_source"])?
+            .code(5)
+            .stderr(predicate::str::contains(
+                "The source `esc/dev.toml` is outside of the `activate.toml` directory.",
+            ));
+        // neither can the directory of a mirror, or the files a glob expands to
+        activate(&["mirrored"])?
+            .code(5)
+            .stderr(predicate::str::contains(
+                "The target `esc` is outside of the `activate.toml` directory.",
+            ));
+        assert_eq!(
+            fs::read_to_string(root.join("shared/dev.toml")).unwrap(),
+            "shared"
+        );
+        fs::remove_file(app.join("esc")).unwrap();
+        std::os::unix::fs::symlink(root.join("shared/dev.toml"), app.join("src/host")).unwrap();
+        activate(&["globbed"])?
+            .code(5)
+            .stderr(predicate::str::contains(
+                "The source `src/host` is outside of the `activate.toml` directory.",
+            ));
+        assert!(!app.join("copies").exists());
+        fs::remove_file(app.join("src/host")).unwrap();
+    }
+
+    activate(&["shared"])?.success();
+    assert_eq!(fs::read_to_string(app.join("app.toml")).unwrap(), "shared");
+    assert_eq!(
+        fs::read_to_string(root.join("shared/app.toml")).unwrap(),
+        "dev"
+    );
+
+    activate(&[])?.success();
+    assert!(fs::symlink_metadata(app.join("app.toml")).is_err());
+    assert!(fs::symlink_metadata(root.join("shared/app.toml")).is_err());
+    assert!(root.join("shared/dev.toml").exists());
+
+    Ok(())
+}
+
+#[test]
+fn protected_environments() -> Result<(), CargoError> {
+    let root = create_project(
+        "protected_environments",
+        &[(
+            "activate.toml",
+            r#"[dev.env]
+HOST = "localhost"
+
+[prod]
+protected = true
+
+[prod.env]
+HOST = "example.com"
+
+[staging]
+protected = "deploy staging"
+
+[broken]
+protected = true
+
+[broken.links]
+"app.toml" = "missing.toml"
+"#,
+        )],
+    );
+    let state_home = root.join("state");
+    let activate = |args: &[&str]| -> Result<assert_cmd::assert::Assert, CargoError> {
+        Ok(assert_cmd::Command::cargo_bin("activate")?
+            .current_dir(&root)
+            .env("XDG_STATE_HOME", &state_home)
+            .args(args)
+            .assert())
+    };
+
+    activate(&["dev"])?.success();
+    activate(&["prod"])?
+        .code(8)
+        .stderr(predicate::str::contains(
+            "The environment 'prod' in `./activate.toml` is protected.",
+        ));
+    assert!(fs::read_to_string(root.join(".activate/active/.env"))
+        .unwrap()
+        .contains("localhost"));
+    assert!(!state_home.exists());
+    // a failed activation is not recorded
+    activate(&["broken", "--yes"])?.code(5);
+    assert!(!state_home.exists());
+
+    activate(&["prod", "--yes"])?
+        .success()
+        .stdout(predicate::str::contains("export HOST=example.com"));
+    let audit_log = fs::read_to_string(state_home.join("activate/audit.log")).unwrap();
+    let entry: serde_json::Value = serde_json::from_str(audit_log.trim()).unwrap();
+    assert_eq!(entry["environment"], "prod");
+    assert_eq!(entry["project"], root.to_str().unwrap());
+    assert!(entry["timestamp"].as_str().unwrap().ends_with('Z'));
+    assert!(entry["user"].is_string());
+
+    let workspace = Workspace::discover(&root, false).unwrap();
+    let plan = workspace.resolve(Some("staging")).unwrap();
+    assert_eq!(plan.confirmation_phrases(), vec!["deploy staging"]);
+    assert!(matches!(
+        plan.apply(&ActivateOptions::default()),
+        Err(Error::ProtectedEnvironment { .. })
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn expiring_environments() -> Result<(), CargoError> {
+    let root = create_project(
+        "expiring_environments",
+        &[
+            (
+                "activate.toml",
+                r#"[prod-readonly]
+ttl = "30m"
+
+[prod-readonly.env]
+TOKEN = "elevated"
+
+[prod-readonly.links]
+"app.toml" = "config/prod.toml"
+
+[dev.env]
+HOST = "localhost"
+"#,
+            ),
+            ("config/prod.toml", "prod"),
+        ],
+    );
+    let activate = |args: &[&str]| -> Result<assert_cmd::assert::Assert, CargoError> {
+        Ok(assert_cmd::Command::cargo_bin("activate")?
+            .current_dir(&root)
+            .args(args)
+            .assert())
+    };
+    let activation_file = root.join(".activate/.state/activation.json");
+    let expire = || {
+        fs::write(
+            &activation_file,
+            r#"{"env_name":"prod-readonly","expires_at":"2000-01-01T00:00:00Z"}"#,
+        )
+        .unwrap()
+    };
+
+    activate(&["prod-readonly"])?.success();
+    activate(&["status"])?
+        .success()
+        .stdout(predicate::str::contains("prod-readonly (expires at "));
+    activate(&["hook"])?.success().stdout("");
+
+    expire();
+    activate(&["hook"])?
+        .success()
+        .stdout("unset TOKEN\n")
+        .stderr(predicate::str::contains(
+            "Notice: The environment `prod-readonly` in `./activate.toml` expired at 2000-01-01T00:00:00Z and was deactivated.",
+        ));
+    assert!(!root.join("app.toml").exists());
+    activate(&["status"])?
+        .success()
+        .stdout(predicate::str::contains("no active environment"));
+
+    activate(&["prod-readonly"])?.success();
+    expire();
+    activate(&["status"])?
+        .success()
+        .stderr(predicate::str::contains("expired at"))
+        .stdout(predicate::str::contains("no active environment"));
+
+    activate(&["prod-readonly"])?.success();
+    expire();
+    activate(&["dev"])?
+        .success()
+        .stdout(predicate::str::contains("unset TOKEN"))
+        .stderr(predicate::str::contains("expired at"));
+    activate(&["status"])?
+        .success()
+        .stdout(predicate::str::contains("dev"))
+        .stdout(predicate::str::contains("expires").not());
+
+    fs::write(root.join("activate.toml"), "[dev]\nttl = \"soon\"\n").unwrap();
+    activate(&["dev"])?
+        .code(3)
+        .stderr(predicate::str::contains("Invalid ttl `soon`."));
+
+    // only the expired descendant is deactivated, also when hooked from below a project
+    let root = create_project(
+        "expiring_descendants",
+        &[
+            ("activate.toml", "[dev.env]\nHOST = \"localhost\"\n"),
+            (
+                "api/activate.toml",
+                "[dev]\nttl = \"30m\"\n\n[dev.env]\nPORT = \"3000\"\n",
+            ),
+            ("api/src/empty", ""),
+        ],
+    );
+    let activate = |dir: &Path, args: &[&str]| -> Result<assert_cmd::assert::Assert, CargoError> {
+        Ok(assert_cmd::Command::cargo_bin("activate")?
+            .current_dir(dir)
+            .args(args)
+            .assert())
+    };
+    activate(&root, &["dev", "-d"])?.success();
+    fs::write(
+        root.join("api/.activate/.state/activation.json"),
+        r#"{"env_name":"dev","expires_at":"2000-01-01T00:00:00Z"}"#,
+    )
+    .unwrap();
+    activate(&root, &["hook", "-d"])?
+        .success()
+        .stdout("unset PORT\nexport HOST=localhost\n");
+    activate(&root, &["status", "-d"])?
+        .success()
+        .stdout(predicate::str::contains(": dev\n"))
+        .stdout(predicate::str::contains("api: no active environment"));
+    assert_eq!(
+        fs::read_to_string(root.join(".activate/active/.env")).unwrap(),
+        "# Generated - managed by `activate`.\n\nHOST=\"localhost\"\n"
+    );
+
+    activate(&root.join("api"), &["dev"])?.success();
+    fs::write(
+        root.join("api/.activate/.state/activation.json"),
+        r#"{"env_name":"dev","expires_at":"2000-01-01T00:00:00Z"}"#,
+    )
+    .unwrap();
+    activate(&root.join("api/src"), &["hook"])?
+        .success()
+        .stdout("unset PORT\n");
+
+    Ok(())
+}
+
+#[test]
+fn default_variables() -> Result<(), CargoError> {
+    let root = create_project(
+        "default_variables",
+        &[(
+            "activate.toml",
+            r#"[dev.env]
+HOST = "localhost"
+RUST_LOG = { default = "info" }
+"#,
+        )],
+    );
+    let activate =
+        |args: &[&str], rust_log: Option<&str>| -> Result<assert_cmd::assert::Assert, CargoError> {
+            let mut command = assert_cmd::Command::cargo_bin("activate")?;
+            command.current_dir(&root).args(args);
+            match rust_log {
+                Some(rust_log) => command.env("RUST_LOG", rust_log),
+                None => command.env_remove("RUST_LOG"),
+            };
+            Ok(command.assert())
+        };
+
+    activate(&["dev"], None)?
+        .success()
+        .stdout("export HOST=localhost\nexport RUST_LOG=info\n");
+    // Set by the previous activation, not by the caller.
+    activate(&["dev"], Some("info"))?
+        .success()
+        .stdout("unset HOST\nunset RUST_LOG\nexport HOST=localhost\nexport RUST_LOG=info\n");
+    activate(&[], Some("info"))?
+        .success()
+        .stdout("unset HOST\nunset RUST_LOG\n");
+
+    activate(&["dev"], Some("debug"))?
+        .success()
+        .stdout("export HOST=localhost\n");
+    assert!(!fs::read_to_string(root.join(".activate/active/.env"))
+        .unwrap()
+        .contains("RUST_LOG"));
+    activate(&[], Some("debug"))?
+        .success()
+        .stdout("unset HOST\n");
+
+    Ok(())
+}